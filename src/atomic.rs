@@ -0,0 +1,386 @@
+use crate::constants;
+use crate::logger;
+use std::fs::{self, File, FileTimes, Metadata};
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Extension given to a target's previous content while a transaction is
+/// being committed.
+const ROLLBACK_EXTENSION: &str = "cclean-orig";
+
+/// Names tried for a temporary file before giving up.
+const TEMP_ATTEMPTS: usize = 100;
+
+/// A file written under a temporary name in its target's directory and moved
+/// over the target only once it is complete, so that a crash or Ctrl-C never
+/// leaves a half-written file behind. The temporary file is removed if the
+/// `AtomicFile` is dropped without being committed. A target that is a
+/// symlink is resolved first, so the file it points to is replaced rather
+/// than the link.
+pub struct AtomicFile {
+    file: File,
+    temp_path: PathBuf,
+    target: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(target: &Path) -> io::Result<Self> {
+        let target = &resolve_symlink(target);
+        let file_name = target.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, constants::ERR_GET_FILENAME)
+        })?;
+        // Unique per process and file, and created exclusively, so that a
+        // leftover from a crashed run, a user's file or a concurrent run
+        // using the same name is never truncated.
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut attempts = 0;
+        loop {
+            let temp_path = target.with_file_name(format!(
+                ".{}.{}-{}{}",
+                file_name.to_string_lossy(),
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed),
+                constants::TEMP_FILE_SUFFIX
+            ));
+            match File::options()
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+            {
+                Ok(file) => {
+                    return Ok(AtomicFile {
+                        file,
+                        temp_path,
+                        target: target.to_path_buf(),
+                        committed: false,
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < TEMP_ATTEMPTS => {
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    /// Flushes the file to disk, gives it the permissions and owner of
+    /// `original` (and its timestamps if `preserve_times` is set), then
+    /// renames it over the target.
+    pub fn commit(mut self, original: Option<&Metadata>, preserve_times: bool) -> io::Result<()> {
+        self.prepare(original, preserve_times)?;
+        fs::rename(&self.temp_path, &self.target)?;
+        self.committed = true;
+        sync_parent(&self.target);
+        Ok(())
+    }
+
+    fn prepare(&self, original: Option<&Metadata>, preserve_times: bool) -> io::Result<()> {
+        if let Some(original) = original {
+            self.file.set_permissions(original.permissions())?;
+            copy_owner(&self.file, original);
+            if preserve_times {
+                self.file.set_times(
+                    FileTimes::new()
+                        .set_accessed(original.accessed()?)
+                        .set_modified(original.modified()?),
+                )?;
+            }
+        }
+        self.file.sync_all()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Outputs staged by `--transactional`, moved into place together or not at
+/// all. Dropping a transaction discards everything staged in it.
+#[derive(Default)]
+pub struct Transaction {
+    staged: Mutex<Vec<Staged>>,
+}
+
+/// A prepared temporary file. Its handle is closed so that large runs do
+/// not run out of file descriptors; the file is removed unless committed.
+struct Staged {
+    temp_path: PathBuf,
+    target: PathBuf,
+    committed: bool,
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// A target replaced during commit, with where its previous content was
+/// moved to, if it existed.
+struct Replaced {
+    target: PathBuf,
+    saved: Option<PathBuf>,
+}
+
+impl Transaction {
+    /// Flushes `file` and copies metadata from `original` as
+    /// [`AtomicFile::commit`] would, but leaves it under its temporary name.
+    pub fn stage(
+        &self,
+        mut file: AtomicFile,
+        original: Option<&Metadata>,
+        preserve_times: bool,
+    ) -> io::Result<()> {
+        file.prepare(original, preserve_times)?;
+        let staged = Staged {
+            temp_path: mem::take(&mut file.temp_path),
+            target: mem::take(&mut file.target),
+            committed: false,
+        };
+        file.committed = true;
+        self.staged
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(staged);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.staged.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Moves every staged file over its target. Each target's previous
+    /// content is set aside first; if any step fails or `interrupted` is
+    /// set, the targets replaced so far are put back and the error returned.
+    pub fn commit(self, interrupted: &AtomicBool) -> io::Result<()> {
+        let staged = self.staged.into_inner().unwrap_or_else(|e| e.into_inner());
+        let mut replaced = Vec::new();
+        let result = staged.into_iter().try_for_each(|mut entry| {
+            if interrupted.load(Ordering::SeqCst) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    constants::ERR_INTERRUPTED,
+                ));
+            }
+            let target = entry.target.clone();
+            let saved = if target.exists() {
+                let saved = entry.temp_path.with_extension(ROLLBACK_EXTENSION);
+                fs::rename(&target, &saved)?;
+                Some(saved)
+            } else {
+                None
+            };
+            replaced.push(Replaced {
+                target: target.clone(),
+                saved,
+            });
+            fs::rename(&entry.temp_path, &target)?;
+            entry.committed = true;
+            Ok(())
+        });
+
+        match result {
+            // Every target is in place, so the commit has succeeded; what is
+            // left is only cleanup.
+            Ok(()) => {
+                for entry in &replaced {
+                    if let Some(ref saved) = entry.saved {
+                        if let Err(e) = fs::remove_file(saved) {
+                            logger::log_warning(&format!(
+                                "{} {}: {}",
+                                constants::MSG_ROLLBACK_COPY_LEFT,
+                                saved.display(),
+                                e
+                            ));
+                        }
+                    }
+                    sync_parent(&entry.target);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                for entry in replaced.iter().rev() {
+                    let _ = match entry.saved {
+                        Some(ref saved) => fs::rename(saved, &entry.target),
+                        None => fs::remove_file(&entry.target),
+                    };
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// `path` with symlinks resolved if it is one, otherwise `path` itself. A
+/// dangling link is returned as is.
+fn resolve_symlink(path: &Path) -> PathBuf {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Best effort: only root can give a file away, but a user can still restore
+/// a group they belong to.
+#[cfg(unix)]
+fn copy_owner(file: &File, original: &Metadata) {
+    use std::os::unix::fs::{fchown, MetadataExt};
+
+    let Ok(current) = file.metadata() else {
+        return;
+    };
+    if (current.uid(), current.gid()) != (original.uid(), original.gid())
+        && fchown(file, Some(original.uid()), Some(original.gid())).is_err()
+    {
+        let _ = fchown(file, None, Some(original.gid()));
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_file: &File, _original: &Metadata) {}
+
+/// Makes the rename itself durable. Directories cannot be opened for syncing
+/// on every platform, so failures are ignored.
+fn sync_parent(path: &Path) {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_commit_replaces_target_and_keeps_metadata() {
+        let dir = std::env::temp_dir().join(format!("cclean-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("run.sh");
+        fs::write(&target, "echo hi # old\n").unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        File::options()
+            .write(true)
+            .open(&target)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&target, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let original = fs::metadata(&target).unwrap();
+
+        let file = AtomicFile::create(&target).unwrap();
+        let temp_path = file.temp_path().to_path_buf();
+        file.file().write_all(b"echo hi\n").unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "echo hi # old\n");
+        file.commit(Some(&original), true).unwrap();
+
+        let written = fs::metadata(&target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "echo hi\n");
+        assert_eq!(written.modified().unwrap(), old);
+        assert_eq!(written.permissions(), original.permissions());
+        assert!(!temp_path.exists());
+
+        let file = AtomicFile::create(&target).unwrap();
+        let temp_path = file.temp_path().to_path_buf();
+        drop(file);
+        assert!(!temp_path.exists());
+
+        // Temporary names never collide with existing files or each other.
+        let first = AtomicFile::create(&target).unwrap();
+        let second = AtomicFile::create(&target).unwrap();
+        assert_ne!(first.temp_path(), second.temp_path());
+        first.file().write_all(b"first\n").unwrap();
+        second.file().write_all(b"second\n").unwrap();
+        assert_eq!(fs::read_to_string(first.temp_path()).unwrap(), "first\n");
+        drop((first, second));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "echo hi\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_commit_writes_through_symlink() {
+        let dir = std::env::temp_dir().join(format!("cclean-symlink-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let (real, link) = (dir.join("real.c"), dir.join("src").join("link.c"));
+        fs::write(&real, "int a; // old\n").unwrap();
+        std::os::unix::fs::symlink("../real.c", &link).unwrap();
+
+        let file = AtomicFile::create(&link).unwrap();
+        file.file().write_all(b"int a;\n").unwrap();
+        file.commit(None, false).unwrap();
+
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&real).unwrap(), "int a;\n");
+        assert_eq!(fs::read_dir(dir.join("src")).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_transaction_rolls_back_on_failure() {
+        let dir = std::env::temp_dir().join(format!("cclean-transaction-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.c"), dir.join("b.c"));
+        fs::write(&a, "a // old\n").unwrap();
+
+        let stage = |transaction: &Transaction, target: &Path, content: &str| {
+            let file = AtomicFile::create(target).unwrap();
+            let temp_path = file.temp_path().to_path_buf();
+            file.file().write_all(content.as_bytes()).unwrap();
+            transaction.stage(file, None, false).unwrap();
+            temp_path
+        };
+
+        // The second target's temporary file disappears before the commit.
+        let transaction = Transaction::default();
+        stage(&transaction, &a, "a\n");
+        let staged_b = stage(&transaction, &b, "b\n");
+        fs::remove_file(staged_b).unwrap();
+        assert!(transaction.commit(&AtomicBool::new(false)).is_err());
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a // old\n");
+        assert!(!b.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let transaction = Transaction::default();
+        stage(&transaction, &a, "a\n");
+        stage(&transaction, &b, "b\n");
+        transaction.commit(&AtomicBool::new(false)).unwrap();
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a\n");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "b\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
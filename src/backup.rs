@@ -0,0 +1,294 @@
+use crate::atomic::AtomicFile;
+use crate::constants;
+use crate::logger;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the original of a file cleaned in place is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupMode {
+    /// `<name>.bak` next to the file.
+    #[default]
+    Sibling,
+    /// The same relative path under the backup directory.
+    Dir,
+    /// The same relative path under a per-run subdirectory of the backup
+    /// directory, so earlier backups are never overwritten.
+    Timestamp,
+}
+
+/// Record of the backups made by one run, read back by `cclean restore`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    created: String,
+    #[serde(default)]
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestEntry {
+    original: PathBuf,
+    backup: PathBuf,
+}
+
+/// Backups made during one run. Paths under the backup directory mirror the
+/// file's path relative to the current directory.
+pub struct Backups {
+    dir: PathBuf,
+    cwd: PathBuf,
+    stamp: String,
+    /// The per-run directory of `timestamp` backups, claimed on first use.
+    run_dir: Mutex<Option<PathBuf>>,
+    entries: Mutex<Vec<ManifestEntry>>,
+}
+
+impl Backups {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let cwd = env::current_dir()?;
+        Ok(Backups {
+            dir: cwd.join(dir),
+            cwd,
+            stamp: timestamp(SystemTime::now()),
+            run_dir: Mutex::new(None),
+            entries: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Copies `file` to its backup location and returns that location.
+    pub fn create(&self, file: &Path, mode: BackupMode) -> Result<PathBuf> {
+        let original = fs::canonicalize(file)?;
+        let backup = match mode {
+            BackupMode::Sibling => {
+                let mut name = file
+                    .file_name()
+                    .context(constants::ERR_GET_FILENAME)?
+                    .to_owned();
+                name.push(constants::BACKUP_SUFFIX);
+                file.with_file_name(name)
+            }
+            BackupMode::Dir => self.dir.join(self.mirrored(&original)),
+            BackupMode::Timestamp => self
+                .run_dir()
+                .with_context(|| {
+                    format!("{} {}", constants::ERR_CREATE_BACKUP, self.dir.display())
+                })?
+                .join(self.mirrored(&original)),
+        };
+
+        let error = || format!("{} {}", constants::ERR_CREATE_BACKUP, backup.display());
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent).with_context(error)?;
+        }
+        fs::copy(file, &backup).with_context(error)?;
+
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(ManifestEntry {
+                original,
+                backup: self.cwd.join(&backup),
+            });
+        Ok(backup)
+    }
+
+    /// Creates the directory named after the run's start time. A run started
+    /// in the same second as another gets `-2`, `-3` and so on instead of
+    /// mixing its backups with the other's.
+    fn run_dir(&self) -> io::Result<PathBuf> {
+        let mut run_dir = self.run_dir.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(ref dir) = *run_dir {
+            return Ok(dir.clone());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut attempt = 1;
+        loop {
+            let dir = match attempt {
+                1 => self.dir.join(&self.stamp),
+                n => self.dir.join(format!("{}-{}", self.stamp, n)),
+            };
+            match fs::create_dir(&dir) {
+                Ok(()) => return Ok(run_dir.insert(dir).clone()),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn mirrored(&self, original: &Path) -> PathBuf {
+        let cwd = fs::canonicalize(&self.cwd).unwrap_or_else(|_| self.cwd.clone());
+        match original.strip_prefix(&cwd) {
+            Ok(relative) => relative.to_path_buf(),
+            // Outside the current directory: keep the whole path, minus its root.
+            Err(_) => original
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .collect(),
+        }
+    }
+
+    /// Removes the backups made by a run that was rolled back, together with
+    /// any directories under the backup directory that are left empty, so the
+    /// tree and the previous run's manifest are as they were.
+    pub fn discard(self) {
+        let files = self.entries.into_inner().unwrap_or_else(|e| e.into_inner());
+        for entry in files.iter().rev() {
+            let _ = fs::remove_file(&entry.backup);
+            let mut dir = entry.backup.parent();
+            while let Some(parent) = dir.filter(|d| d.starts_with(&self.dir)) {
+                if fs::remove_dir(parent).is_err() {
+                    break;
+                }
+                dir = parent.parent();
+            }
+        }
+    }
+
+    /// Writes the manifest for `cclean restore`, replacing the previous
+    /// run's. Does nothing if no backups were made.
+    pub fn save_manifest(self) -> Result<()> {
+        let files = self.entries.into_inner().unwrap_or_else(|e| e.into_inner());
+        if files.is_empty() {
+            return Ok(());
+        }
+        let manifest = Manifest {
+            created: self.stamp,
+            files,
+        };
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(constants::MANIFEST_FILE_NAME);
+        let file = AtomicFile::create(&path)?;
+        io::Write::write_all(&mut file.file(), toml::to_string(&manifest)?.as_bytes())?;
+        file.commit(None, false)?;
+        Ok(())
+    }
+}
+
+/// Puts back every original recorded in the manifest under `dir`.
+pub fn restore(dir: &Path) -> Result<()> {
+    let path = dir.join(constants::MANIFEST_FILE_NAME);
+    let source = fs::read_to_string(&path).context(format!(
+        "{} {}",
+        constants::ERR_NO_MANIFEST,
+        path.display()
+    ))?;
+    let manifest: Manifest = toml::from_str(&source).context(format!(
+        "{} {}",
+        constants::ERR_INVALID_MANIFEST,
+        path.display()
+    ))?;
+
+    logger::log_restore_run(&manifest.created);
+    let mut failed = 0;
+    for entry in &manifest.files {
+        match restore_file(entry) {
+            Ok(()) => logger::log_restored(
+                &entry.original.display().to_string(),
+                &entry.backup.display().to_string(),
+            ),
+            Err(e) => {
+                logger::error_msg(&format!("{}: {:#}", entry.original.display(), e));
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} {}", failed, constants::ERR_FILES_FAILED);
+    }
+    Ok(())
+}
+
+fn restore_file(entry: &ManifestEntry) -> Result<()> {
+    let metadata = fs::metadata(&entry.backup).context(format!(
+        "{} {}",
+        constants::ERR_READ_FILE,
+        entry.backup.display()
+    ))?;
+    let target = AtomicFile::create(&entry.original)?;
+    io::copy(&mut File::open(&entry.backup)?, &mut target.file())?;
+    target.commit(Some(&metadata), true)?;
+    Ok(())
+}
+
+/// UTC time as `YYYYMMDD-HHMMSS`, used to name per-run backup directories.
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rest / 3_600,
+        rest % 3_600 / 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(1_709_210_096)),
+            "20240229-123456"
+        );
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let root = env::temp_dir().join(format!("cclean-backup-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+        let file = root.join("src").join("a.c");
+        fs::write(&file, "int a; // original\n").unwrap();
+
+        let dir = root.join("backups");
+        let backups = Backups::new(&dir).unwrap();
+        let sibling = backups.create(&file, BackupMode::Sibling).unwrap();
+        let mirrored = backups.create(&file, BackupMode::Dir).unwrap();
+        assert_eq!(sibling, root.join("src").join("a.c.bak"));
+        assert!(mirrored.starts_with(&dir) && mirrored.ends_with("src/a.c"));
+        backups.save_manifest().unwrap();
+
+        fs::write(&file, "int a; \n").unwrap();
+        restore(&dir).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "int a; // original\n");
+
+        // Two runs started in the same second keep separate directories.
+        let first = Backups::new(&dir).unwrap();
+        let mut second = Backups::new(&dir).unwrap();
+        second.stamp = first.stamp.clone();
+        let a = first.create(&file, BackupMode::Timestamp).unwrap();
+        let b = second.create(&file, BackupMode::Timestamp).unwrap();
+        assert!(a.starts_with(dir.join(&first.stamp)));
+        assert!(b.starts_with(dir.join(format!("{}-2", first.stamp))));
+        assert_eq!(first.create(&file, BackupMode::Timestamp).unwrap(), a);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use crate::atomic::AtomicFile;
+use crate::constants;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// What a previous run produced for one input file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub input_hash: String,
+    pub output_hash: String,
+    pub output: String,
+    pub settings: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    #[serde(default)]
+    files: BTreeMap<String, Entry>,
+}
+
+/// Persistent record of cleaned files used by `--incremental`.
+///
+/// Entries are keyed by input path, resolved and made relative to the
+/// directory of the cache file, so that the same file has the same key
+/// however it is named on the command line. An input is unchanged when its
+/// content hash and settings match the entry and the output it produced is
+/// still on disk untouched.
+pub struct Cache {
+    path: PathBuf,
+    /// The directory holding the cache file, resolved.
+    root: PathBuf,
+    previous: BTreeMap<String, Entry>,
+    updated: Mutex<BTreeMap<String, Entry>>,
+    /// Keys of the inputs looked up during this run.
+    seen: Mutex<HashSet<String>>,
+}
+
+impl Cache {
+    /// Loads the cache at `path`. A missing or unreadable cache is treated as
+    /// empty, which only means every file is cleaned again.
+    pub fn load(path: PathBuf) -> Self {
+        let previous = fs::read_to_string(&path)
+            .ok()
+            .and_then(|source| toml::from_str::<CacheFile>(&source).ok())
+            .unwrap_or_default()
+            .files;
+
+        let root = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => canonical(parent),
+            _ => canonical(Path::new(".")),
+        };
+        Self {
+            path,
+            root,
+            previous,
+            updated: Mutex::new(BTreeMap::new()),
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Returns true if `input`, whose content hashes to `input_hash`, was
+    /// cleaned into `output` with `settings` by a previous run and neither
+    /// side has changed since.
+    pub fn is_unchanged(
+        &self,
+        input: &Path,
+        input_hash: &str,
+        output: &Path,
+        settings: &str,
+    ) -> io::Result<bool> {
+        let input_key = self.key(input);
+        let entry = self.previous.get(&input_key);
+        self.seen
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(input_key);
+        let Some(entry) = entry else {
+            return Ok(false);
+        };
+        if entry.settings != settings || entry.output != self.key(output) {
+            return Ok(false);
+        }
+
+        // Cleaning in place replaces the input with the output it produced.
+        if input == output {
+            return Ok(input_hash == entry.output_hash);
+        }
+        if input_hash != entry.input_hash {
+            return Ok(false);
+        }
+
+        match hash_file(output) {
+            Ok(hash) => Ok(hash == entry.output_hash),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records that `input` was cleaned into `output`. The output is hashed
+    /// from `written`, which is the staged temporary file while a transaction
+    /// has not been committed yet and `output` itself otherwise.
+    pub fn record(
+        &self,
+        input: &Path,
+        input_hash: String,
+        output: &Path,
+        written: &Path,
+        settings: &str,
+    ) -> io::Result<()> {
+        let entry = Entry {
+            input_hash,
+            output_hash: hash_file(written)?,
+            output: self.key(output),
+            settings: settings.to_string(),
+        };
+        self.updated
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(self.key(input), entry);
+        Ok(())
+    }
+
+    /// Writes the previous entries merged with the ones recorded in this run.
+    /// With `prune`, set after a run that went through the whole tree,
+    /// entries for inputs the run did not come across are dropped, since
+    /// those files were deleted or are no longer selected.
+    pub fn save(self, prune: bool) -> Result<()> {
+        let mut files = self.previous;
+        if prune {
+            let seen = self.seen.into_inner().unwrap_or_else(|e| e.into_inner());
+            files.retain(|key, _| seen.contains(key));
+        }
+        files.extend(self.updated.into_inner().unwrap_or_else(|e| e.into_inner()));
+
+        let error = || format!("{} {}", constants::ERR_WRITE_FILE, self.path.display());
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).with_context(error)?;
+        }
+        let source = toml::to_string(&CacheFile { files })?;
+        let file = AtomicFile::create(&self.path).with_context(error)?;
+        io::Write::write_all(&mut file.file(), source.as_bytes()).with_context(error)?;
+        file.commit(None, false).with_context(error)
+    }
+
+    /// `path` relative to the cache directory, with `..` to leave it and `/`
+    /// separators, so that a tree keeps its cache when it is moved. Paths on
+    /// another drive keep their resolved form.
+    fn key(&self, path: &Path) -> String {
+        let path = canonical(path);
+        let (mut to, mut from) = (
+            path.components().peekable(),
+            self.root.components().peekable(),
+        );
+        if to.peek() != from.peek() {
+            return path.to_string_lossy().into_owned();
+        }
+        while to.peek().is_some() && to.peek() == from.peek() {
+            to.next();
+            from.next();
+        }
+        from.map(|_| "..".into())
+            .chain(to.map(|c| c.as_os_str().to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// `path` made absolute with symlinks resolved as far as it exists; the rest,
+/// such as an output that is not written yet, is appended as given.
+fn canonical(path: &Path) -> PathBuf {
+    if let Ok(resolved) = fs::canonicalize(path) {
+        return resolved;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            canonical(parent).join(name)
+        }
+        (Some(_), Some(name)) => canonical(Path::new(".")).join(name),
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+/// 64-bit FNV-1a hash of a file's contents as a hex string. It is stable
+/// across builds, unlike `DefaultHasher`, so caches survive upgrades.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut hash = OFFSET;
+    loop {
+        let read = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for &b in &buf[..read] {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_key(dir: &Path, path: &Path) -> String {
+        Cache::load(dir.join(constants::CACHE_FILE_NAME)).key(path)
+    }
+
+    #[test]
+    fn test_unchanged_until_input_or_output_changes() {
+        let dir = std::env::temp_dir().join(format!("cclean-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("a.c");
+        let output = dir.join("out.c");
+        let cache_path = dir.join(constants::CACHE_FILE_NAME);
+        fs::write(&input, "int a; // x\n").unwrap();
+        fs::write(&output, "int a; \n").unwrap();
+
+        let cache = Cache::load(cache_path.clone());
+        let hash = hash_file(&input).unwrap();
+        assert!(!cache.is_unchanged(&input, &hash, &output, "C").unwrap());
+        cache
+            .record(&input, hash.clone(), &output, &output, "C")
+            .unwrap();
+        cache.save(false).unwrap();
+
+        let cache = Cache::load(cache_path);
+        assert!(cache.is_unchanged(&input, &hash, &output, "C").unwrap());
+        assert!(!cache.is_unchanged(&input, &hash, &output, "C++").unwrap());
+        assert!(!cache.is_unchanged(&input, "0", &output, "C").unwrap());
+        fs::write(&output, "edited\n").unwrap();
+        assert!(!cache.is_unchanged(&input, &hash, &output, "C").unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keys_and_pruning() {
+        let dir = std::env::temp_dir().join(format!("cclean-cache-keys-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let cache_path = dir.join(constants::CACHE_FILE_NAME);
+        let (kept, gone) = (dir.join("src/a.c"), dir.join("src/b.c"));
+        for input in [&kept, &gone] {
+            fs::write(input, "int a;\n").unwrap();
+        }
+        let hash = hash_file(&kept).unwrap();
+
+        let cache = Cache::load(cache_path.clone());
+        for input in [&kept, &gone] {
+            cache
+                .record(input, hash.clone(), input, input, "C")
+                .unwrap();
+        }
+        cache.save(false).unwrap();
+        assert_eq!(cache_key(&dir.join("out"), &kept), "../src/a.c");
+        let saved = fs::read_to_string(&cache_path).unwrap();
+        assert!(saved.contains("\"src/a.c\""), "{}", saved);
+        assert!(!saved.contains(dir.to_str().unwrap()), "{}", saved);
+
+        // The same file named another way, and a run that did not see b.c
+        // but did not cover the whole tree either.
+        let cache = Cache::load(cache_path.clone());
+        let other_name = dir.join("src/../src/./a.c");
+        assert!(cache
+            .is_unchanged(&other_name, &hash, &other_name, "C")
+            .unwrap());
+        cache.save(false).unwrap();
+        assert!(fs::read_to_string(&cache_path).unwrap().contains("src/b.c"));
+
+        let cache = Cache::load(cache_path.clone());
+        assert!(cache.is_unchanged(&kept, &hash, &kept, "C").unwrap());
+        cache.save(true).unwrap();
+        let saved = fs::read_to_string(&cache_path).unwrap();
+        assert!(
+            saved.contains("src/a.c") && !saved.contains("src/b.c"),
+            "{}",
+            saved
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
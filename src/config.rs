@@ -0,0 +1,486 @@
+use crate::backup::BackupMode;
+use crate::cleaner::CleanOptions;
+use crate::constants;
+use crate::encoding;
+use crate::language::Language;
+use crate::logger;
+use crate::Cli;
+use anyhow::{Context, Result};
+use encoding_rs::Encoding;
+use globset::{Glob, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Contents of a `.cclean.toml` file. Every key is optional; unset keys fall
+/// back to the nearest config in a parent directory.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub recursive: Option<bool>,
+    pub in_place: Option<bool>,
+    pub backup: Option<bool>,
+    pub backup_mode: Option<BackupMode>,
+    /// Relative to the directory of the config file.
+    pub backup_dir: Option<PathBuf>,
+    pub verify: Option<bool>,
+    pub force: Option<bool>,
+    pub preserve_mtime: Option<bool>,
+    pub incremental: Option<bool>,
+    pub transactional: Option<bool>,
+    pub jobs: Option<usize>,
+    pub extensions: Option<Vec<String>>,
+    pub collapse_empty_lines: Option<bool>,
+    /// Encoding label for files without a BOM, e.g. `shift_jis`.
+    pub encoding: Option<String>,
+    /// Globs relative to the directory of the config file.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// File extension to language name, e.g. `h = "cpp"`.
+    #[serde(default)]
+    pub mappings: BTreeMap<String, String>,
+    #[serde(default)]
+    pub languages: BTreeMap<String, LanguageConfig>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageConfig {
+    pub collapse_empty_lines: Option<bool>,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path).context(format!(
+            "{} {}",
+            constants::ERR_READ_FILE,
+            path.display()
+        ))?;
+        toml::from_str(&source).context(format!(
+            "{} {}",
+            constants::ERR_INVALID_CONFIG,
+            path.display()
+        ))
+    }
+}
+
+/// Settings in effect for one directory: every `.cclean.toml` from the
+/// filesystem root down to it, nearer files overriding farther ones.
+#[derive(Debug, Default, Clone)]
+pub struct Scope {
+    config: Config,
+    excludes: Vec<(PathBuf, GlobMatcher)>,
+    /// `.gitignore` and `.ignore` files from the enclosing repository, outermost first.
+    ignores: Vec<Gitignore>,
+    mappings: HashMap<String, Language>,
+    languages: HashMap<Language, LanguageConfig>,
+    encoding: Option<&'static Encoding>,
+}
+
+/// What to do with a single file once CLI flags and config files are merged.
+#[derive(Debug, Clone)]
+pub struct FileSettings {
+    /// Only used when cleaning in place.
+    pub backup: Option<BackupMode>,
+    pub verify: bool,
+    pub force: bool,
+    pub preserve_mtime: bool,
+    /// Set by `--encoding` or the config; otherwise detected per file.
+    pub encoding: Option<&'static Encoding>,
+    pub options: CleanOptions,
+}
+
+impl Scope {
+    fn overlay(&mut self, dir: &Path, path: &Path, child: Config) -> Result<()> {
+        let context = || format!("{} {}", constants::ERR_INVALID_CONFIG, path.display());
+
+        for pattern in &child.exclude {
+            let matcher = Glob::new(pattern).with_context(context)?.compile_matcher();
+            self.excludes.push((dir.to_path_buf(), matcher));
+        }
+        for (extension, name) in &child.mappings {
+            let language = Language::from_name(name)
+                .with_context(|| format!("{} `{}`", constants::ERR_UNKNOWN_LANGUAGE, name))
+                .with_context(context)?;
+            self.mappings.insert(extension.to_lowercase(), language);
+        }
+        if let Some(ref label) = child.encoding {
+            self.encoding = Some(
+                encoding::parse_label(label)
+                    .map_err(anyhow::Error::msg)
+                    .with_context(context)?,
+            );
+        }
+        for (name, options) in child.languages {
+            let language = Language::from_name(&name)
+                .with_context(|| format!("{} `{}`", constants::ERR_UNKNOWN_LANGUAGE, name))
+                .with_context(context)?;
+            let entry = self.languages.entry(language).or_default();
+            if options.collapse_empty_lines.is_some() {
+                entry.collapse_empty_lines = options.collapse_empty_lines;
+            }
+        }
+
+        let config = &mut self.config;
+        config.recursive = child.recursive.or(config.recursive);
+        config.in_place = child.in_place.or(config.in_place);
+        config.backup = child.backup.or(config.backup);
+        config.backup_mode = child.backup_mode.or(config.backup_mode);
+        if let Some(backup_dir) = child.backup_dir {
+            config.backup_dir = Some(dir.join(backup_dir));
+        }
+        config.verify = child.verify.or(config.verify);
+        config.force = child.force.or(config.force);
+        config.preserve_mtime = child.preserve_mtime.or(config.preserve_mtime);
+        config.incremental = child.incremental.or(config.incremental);
+        config.transactional = child.transactional.or(config.transactional);
+        config.jobs = child.jobs.or(config.jobs);
+        config.extensions = child.extensions.or(config.extensions.take());
+        config.collapse_empty_lines = child.collapse_empty_lines.or(config.collapse_empty_lines);
+        Ok(())
+    }
+
+    /// Ignore files stop at the root of a repository, where
+    /// `.git/info/exclude` is picked up as well.
+    fn add_ignore_files(&mut self, dir: &Path) {
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut files = Vec::new();
+        let git_dir = dir.join(constants::GIT_DIR_NAME);
+        if git_dir.exists() {
+            self.ignores.clear();
+            files.push(git_dir.join("info").join("exclude"));
+        }
+        files.extend(constants::IGNORE_FILE_NAMES.map(|name| dir.join(name)));
+        for path in files {
+            if path.is_file() {
+                if let Some(err) = builder.add(&path) {
+                    logger::log_warning(&format!("{}: {}", path.display(), err));
+                }
+            }
+        }
+        match builder.build() {
+            Ok(ignore) if !ignore.is_empty() => self.ignores.push(ignore),
+            Ok(_) => {}
+            Err(err) => logger::log_warning(&err.to_string()),
+        }
+    }
+
+    /// Applies run-wide defaults to flags that were not given on the command line.
+    pub fn apply_defaults(&self, cli: &mut Cli) {
+        let config = &self.config;
+        cli.recursive = flag(cli.recursive, cli.no_recursive, config.recursive);
+        // An explicit output is a request not to touch the sources.
+        cli.in_place = flag(
+            cli.in_place,
+            cli.no_in_place || cli.output.is_some(),
+            config.in_place,
+        );
+        cli.incremental = flag(cli.incremental, cli.no_incremental, config.incremental);
+        cli.transactional = flag(
+            cli.transactional,
+            cli.no_transactional,
+            config.transactional,
+        );
+        cli.jobs = cli.jobs.or(self.config.jobs);
+        cli.backup_dir = Some(cli.backup_dir.take().unwrap_or_else(|| self.backup_dir()));
+    }
+
+    /// Whether `other` sets the same run-wide defaults, so that either can be
+    /// applied to a run covering both.
+    pub fn same_run_defaults(&self, other: &Scope) -> bool {
+        let defaults = |scope: &Scope| {
+            let config = &scope.config;
+            (
+                config.recursive.unwrap_or(false),
+                config.in_place.unwrap_or(false),
+                config.incremental.unwrap_or(false),
+                config.transactional.unwrap_or(false),
+                config.jobs,
+                scope.backup_dir(),
+            )
+        };
+        defaults(self) == defaults(other)
+    }
+
+    pub fn backup_dir(&self) -> PathBuf {
+        self.config
+            .backup_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(constants::BACKUP_DIR_NAME))
+    }
+
+    /// `path` must be absolute, like the directories scopes are resolved for.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.excludes.iter().any(|(dir, matcher)| {
+            path.strip_prefix(dir)
+                .is_ok_and(|relative| matcher.is_match(relative))
+        })
+    }
+
+    /// Whether `path` is matched by an ignore file. `path` must be absolute.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for ignore in self.ignores.iter().rev() {
+            let matched = ignore.matched_path_or_any_parents(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+        false
+    }
+
+    pub fn accepts_extension(&self, path: &Path, cli: &Cli) -> bool {
+        let extensions: Vec<String> = match (&cli.extensions, &self.config.extensions) {
+            (Some(exts), _) => exts.split(',').map(|s| s.trim().to_string()).collect(),
+            (None, Some(exts)) => exts.clone(),
+            (None, None) => return true,
+        };
+        path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|ext| extensions.iter().any(|e| e == ext))
+    }
+
+    /// Language for `path` from the config's extension mappings, falling back
+    /// to the registry. `--lang` is handled by the caller.
+    pub fn language_for(&self, path: &Path) -> Option<Language> {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|ext| self.mappings.get(&ext.to_lowercase()).copied())
+            .or_else(|| Language::from_path(path))
+    }
+
+    pub fn file_settings(&self, cli: &Cli, language: Language) -> FileSettings {
+        let mut options = CleanOptions::default();
+        let collapse = self
+            .languages
+            .get(&language)
+            .and_then(|l| l.collapse_empty_lines)
+            .or(self.config.collapse_empty_lines);
+        if let Some(collapse) = collapse {
+            options.collapse_empty_lines = collapse;
+        }
+
+        FileSettings {
+            backup: (cli.backup_mode.is_some()
+                || flag(cli.backup, cli.no_backup, self.config.backup))
+            .then(|| {
+                cli.backup_mode
+                    .or(self.config.backup_mode)
+                    .unwrap_or_default()
+            }),
+            verify: flag(cli.verify, cli.no_verify, self.config.verify),
+            force: flag(cli.force, cli.no_force, self.config.force),
+            preserve_mtime: flag(
+                cli.preserve_mtime,
+                cli.no_preserve_mtime,
+                self.config.preserve_mtime,
+            ),
+            encoding: cli.encoding.or(self.encoding),
+            options,
+        }
+    }
+}
+
+/// Finds and merges `.cclean.toml` files, caching the result per directory.
+#[derive(Default)]
+pub struct Resolver {
+    scopes: HashMap<PathBuf, Arc<Scope>>,
+}
+
+impl Resolver {
+    /// Returns the scope for `dir`, which must be absolute.
+    pub fn scope(&mut self, dir: &Path) -> Result<Arc<Scope>> {
+        if let Some(scope) = self.scopes.get(dir) {
+            return Ok(Arc::clone(scope));
+        }
+
+        let mut scope = match dir.parent() {
+            Some(parent) => (*self.scope(parent)?).clone(),
+            None => Scope::default(),
+        };
+        let path = dir.join(constants::CONFIG_FILE_NAME);
+        if path.is_file() {
+            scope.overlay(dir, &path, Config::from_file(&path)?)?;
+            logger::debug(&format!(
+                "{} {}",
+                constants::MSG_CONFIG_LOADED,
+                path.display()
+            ));
+        }
+        scope.add_ignore_files(dir);
+
+        let scope = Arc::new(scope);
+        self.scopes.insert(dir.to_path_buf(), Arc::clone(&scope));
+        Ok(scope)
+    }
+
+    /// Scope of the directory containing `path`, or of `path` itself if it
+    /// is a directory.
+    pub fn scope_for(&mut self, path: &Path) -> Result<Arc<Scope>> {
+        let absolute = fs::canonicalize(path).context(format!(
+            "{} {}",
+            constants::ERR_PATH_NOT_EXIST,
+            path.display()
+        ))?;
+        if absolute.is_dir() {
+            self.scope(&absolute)
+        } else {
+            self.scope(absolute.parent().unwrap_or(&absolute))
+        }
+    }
+}
+
+/// A flag given on the command line as `--x` (`on`) or `--no-x` (`off`) wins
+/// either way; the config only fills in a flag that was not given.
+fn flag(on: bool, off: bool, config: Option<bool>) -> bool {
+    on || (!off && config.unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_nested_configs_override_parents() {
+        let root = std::env::temp_dir().join(format!("cclean-config-{}", std::process::id()));
+        let nested = root.join("vendor").join("lib");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(
+            root.join(constants::CONFIG_FILE_NAME),
+            "verify = true\nexclude = [\"*.min.js\"]\n[mappings]\nh = \"cpp\"\n[languages.python]\ncollapse_empty_lines = false\n",
+        )
+        .unwrap();
+        fs::write(
+            nested.join(constants::CONFIG_FILE_NAME),
+            "verify = false\nexclude = [\"gen/**\"]\n",
+        )
+        .unwrap();
+
+        let root = fs::canonicalize(&root).unwrap();
+        let nested = root.join("vendor").join("lib");
+        let cli = Cli::parse_from(["cclean", "."]);
+        let mut resolver = Resolver::default();
+
+        let top = resolver.scope(&root).unwrap();
+        assert!(top.file_settings(&cli, Language::C).verify);
+        assert!(
+            top.file_settings(&cli, Language::C)
+                .options
+                .collapse_empty_lines
+        );
+        assert!(
+            !top.file_settings(&cli, Language::Python)
+                .options
+                .collapse_empty_lines
+        );
+        assert_eq!(top.language_for(Path::new("a.h")), Some(Language::Cpp));
+
+        let inner = resolver.scope(&nested).unwrap();
+        assert!(!inner.file_settings(&cli, Language::C).verify);
+        assert!(inner.is_excluded(&nested.join("x").join("app.min.js")));
+        assert!(inner.is_excluded(&nested.join("gen").join("a.c")));
+        assert!(!top.is_excluded(&root.join("gen").join("a.c")));
+        assert_eq!(inner.language_for(Path::new("a.h")), Some(Language::Cpp));
+
+        let cli = Cli::parse_from(["cclean", ".", "--verify"]);
+        assert!(inner.file_settings(&cli, Language::C).verify);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_files_stop_at_repository_root() {
+        let outer = std::env::temp_dir().join(format!("cclean-ignore-{}", std::process::id()));
+        let repo = outer.join("repo");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::create_dir_all(repo.join("src")).unwrap();
+        fs::write(outer.join(".gitignore"), "*.c\n").unwrap();
+        fs::write(repo.join(".gitignore"), "build/\n*.gen.js\n").unwrap();
+        fs::write(repo.join("src").join(".ignore"), "!keep.gen.js\n").unwrap();
+
+        let repo = fs::canonicalize(&repo).unwrap();
+        let src = repo.join("src");
+        let mut resolver = Resolver::default();
+        let scope = resolver.scope(&src).unwrap();
+        assert!(!scope.is_ignored(&src.join("main.c"), false));
+        assert!(scope.is_ignored(&src.join("a.gen.js"), false));
+        assert!(!scope.is_ignored(&src.join("keep.gen.js"), false));
+        assert!(scope.is_ignored(&src.join("build"), true));
+        assert!(!scope.is_ignored(&src.join("build"), false));
+
+        fs::remove_dir_all(&outer).unwrap();
+    }
+
+    #[test]
+    fn test_run_defaults_must_agree() {
+        let root = std::env::temp_dir().join(format!("cclean-run-config-{}", std::process::id()));
+        for dir in ["a", "b", "c"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::write(
+            root.join("a").join(constants::CONFIG_FILE_NAME),
+            "in_place = true\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("c").join(constants::CONFIG_FILE_NAME),
+            "in_place = false\nverify = true\n",
+        )
+        .unwrap();
+
+        let mut resolver = Resolver::default();
+        let a = resolver.scope_for(&root.join("a")).unwrap();
+        let b = resolver.scope_for(&root.join("b")).unwrap();
+        let c = resolver.scope_for(&root.join("c")).unwrap();
+        assert!(!a.same_run_defaults(&b));
+        assert!(b.same_run_defaults(&c));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_command_line_overrides_config() {
+        let root = std::env::temp_dir().join(format!("cclean-override-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join(constants::CONFIG_FILE_NAME),
+            "in_place = true\nrecursive = true\nverify = true\n",
+        )
+        .unwrap();
+        let mut resolver = Resolver::default();
+        let scope = resolver.scope_for(&root).unwrap();
+
+        let mut cli = Cli::parse_from(["cclean", "."]);
+        scope.apply_defaults(&mut cli);
+        assert!(cli.in_place && cli.recursive);
+        assert!(scope.file_settings(&cli, Language::C).verify);
+
+        // An explicit output must not clean the sources in place.
+        let mut cli = Cli::parse_from(["cclean", ".", "-o", "out"]);
+        scope.apply_defaults(&mut cli);
+        assert!(!cli.in_place && cli.recursive);
+
+        let mut cli = Cli::parse_from([
+            "cclean",
+            ".",
+            "--no-in-place",
+            "--no-recursive",
+            "--no-verify",
+        ]);
+        scope.apply_defaults(&mut cli);
+        assert!(!cli.in_place && !cli.recursive);
+        assert!(!scope.file_settings(&cli, Language::C).verify);
+
+        // The last of a flag and its negation wins.
+        let cli = Cli::parse_from(["cclean", ".", "--no-verify", "--verify"]);
+        assert!(scope.file_settings(&cli, Language::C).verify);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
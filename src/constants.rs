@@ -1,6 +1,3 @@
-use regex::Regex;
-use std::sync::OnceLock;
-
 // App Info
 pub const APP_NAME: &str = "Code Comment Cleaning Tool";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -9,15 +6,53 @@ pub const APP_SEPARATOR_CHAR: &str = "=";
 
 // Output Messages
 pub const MSG_PROCESSING_COMPLETED: &str = "\nProcessing completed!";
+pub const MSG_RESTORE_COMPLETED: &str = "\nRestore completed!";
 pub const MSG_DRY_RUN_SKIP: &str = "  [Dry run - file not modified]";
+pub const MSG_UNCHANGED_SKIP: &str = "  [Unchanged since last run - skipped]";
+pub const MSG_BINARY_SKIP: &str = "  [Binary file - skipped]";
+pub const MSG_CLEAN: &str = "  [No comments]";
+pub const MSG_WOULD_CHANGE: &str = "  Would change, comments found:";
+pub const MSG_BINARY_PASSTHROUGH: &str = "input looks binary, copied unchanged";
 pub const MSG_BACKUP_PREFIX: &str = "  Backup:";
 pub const MSG_OUTPUT_PREFIX: &str = "  Output:";
+pub const MSG_PATCH_PREFIX: &str = "Patch:";
+pub const MSG_ROLLBACK_COPY_LEFT: &str = "Cannot remove the previous content of a committed file:";
+pub const MSG_NOT_IN_PATCH: &str = "UTF-16 files cannot be patched, left out of the patch";
+pub const MSG_OUTSIDE_PATCH: &str =
+    "outside the current directory, so git apply cannot patch it; left out of the patch";
 pub const MSG_PROCESSING_PREFIX: &str = "Processing:";
 pub const MSG_SKIPPING_PREFIX: &str = "Skipping:";
+pub const MSG_IGNORING_PREFIX: &str = "Ignoring:";
+pub const MSG_COMMENTS_REMOVED: &str = "  Comments removed:";
+pub const MSG_DEBUG_PREFIX: &str = "Debug:";
+pub const MSG_CONFIG_LOADED: &str = "loaded config";
+pub const MSG_WORKERS: &str = "worker threads:";
+pub const MSG_CACHE_FILE: &str = "cache file:";
+pub const MSG_COMMITTED: &str = "Transaction committed:";
+pub const MSG_ROLLED_BACK: &str = "Transaction rolled back, no files were changed. Staged:";
+pub const MSG_RESTORED_PREFIX: &str = "Restored:";
+pub const MSG_RESTORE_RUN: &str = "Restoring backups made at";
 pub const MSG_ERROR_PREFIX: &str = "Error:";
+pub const MSG_WARNING_PREFIX: &str = "  Warning:";
 pub const MSG_PROCESSED_SUMMARY: &str = "Processed";
 pub const MSG_SKIPPED_SUMMARY: &str = "skipped";
+pub const MSG_UNCHANGED_SUMMARY: &str = "unchanged";
+pub const MSG_IGNORED_SUMMARY: &str = "ignored";
+pub const MSG_BINARY_SUMMARY: &str = "binary";
+pub const MSG_WOULD_CHANGE_SUMMARY: &str = "would change";
+pub const MSG_FAILED_SUMMARY: &str = "failed";
+pub const MSG_NOT_RUN_SUMMARY: &str = "not run";
 pub const MSG_FILES_SUFFIX: &str = "files";
+pub const MSG_PATHS_SUFFIX: &str = "paths";
+
+// Report Reasons
+pub const REASON_BINARY: &str = "binary";
+pub const REASON_UNCHANGED: &str = "unchanged";
+pub const REASON_UNSUPPORTED: &str = "unsupported";
+
+// Progress Bar
+pub const PROGRESS_TEMPLATE: &str =
+    "{prefix} [{bar:30}] {bytes}/{total_bytes}, {eta} left {wide_msg}";
 
 // Error Messages
 pub const ERR_PATH_NOT_EXIST: &str = "Path does not exist:";
@@ -27,23 +62,40 @@ pub const ERR_READ_FILE: &str = "Cannot read file:";
 pub const ERR_CREATE_BACKUP: &str = "Cannot create backup file:";
 pub const ERR_WRITE_FILE: &str = "Cannot write file:";
 pub const ERR_GET_FILENAME: &str = "Cannot get filename";
+pub const ERR_VERIFY_FAILED: &str = "Verification failed, file not written:";
+pub const ERR_STDIN_LANGUAGE: &str =
+    "Cannot determine the language of standard input, please use --lang or --stdin-filename";
+pub const ERR_STDIN_WITH_PATHS: &str = "- (standard input) cannot be combined with other paths";
+pub const ERR_CONFIG_CONFLICT: &str =
+    "Config files set different run-wide options (recursive, in_place, incremental, transactional, jobs, backup_dir) for:";
+pub const ERR_OUTPUT_CONFLICT: &str = "Several files would be written to the same output:";
+pub const ERR_NO_MATCH: &str = "No files match pattern:";
+pub const ERR_INVALID_PATTERN: &str = "Invalid glob pattern:";
+pub const ERR_FILES_FAILED: &str = "file(s) could not be processed";
+pub const ERR_NOT_RUN: &str = "file(s) not processed after the first failure (--fail-fast)";
+pub const ERR_CHECK_FAILED: &str = "file(s) would be changed by cleaning";
+pub const ERR_UNKNOWN_ENCODING: &str = "Unknown encoding";
+pub const ERR_DECODE: &str = "Cannot decode file, use --encoding to set its encoding. Tried";
+pub const ERR_ENCODE: &str = "Cannot encode";
+pub const ERR_NO_MANIFEST: &str = "No backup manifest found:";
+pub const ERR_INVALID_MANIFEST: &str = "Invalid backup manifest:";
+pub const ERR_INTERRUPTED: &str = "Interrupted";
+pub const ERR_COMMIT_FAILED: &str = "Could not commit the transaction";
+pub const ERR_LOG_FILE: &str = "Cannot create log file:";
+pub const ERR_INVALID_CONFIG: &str = "Invalid config file:";
+pub const ERR_UNKNOWN_LANGUAGE: &str = "Unknown language";
+pub const ERR_UNTERMINATED: &str = "Refusing to write:";
+pub const HINT_FORCE: &str = "Use --force to write it anyway";
 
-// Regex Patterns
-pub const HTML_COMMENT_REGEX_STR: &str = r"<!--[\s\S]*?-->";
-pub const SCRIPT_TAG_REGEX_STR: &str = r"(?s)<script[^>]*>(.*?)</script>";
-pub const STYLE_TAG_REGEX_STR: &str = r"(?s)<style[^>]*>(.*?)</style>";
-
-pub fn html_comment_regex() -> &'static Regex {
-    static RE: OnceLock<Regex> = OnceLock::new();
-    RE.get_or_init(|| Regex::new(HTML_COMMENT_REGEX_STR).unwrap())
-}
-
-pub fn script_tag_regex() -> &'static Regex {
-    static RE: OnceLock<Regex> = OnceLock::new();
-    RE.get_or_init(|| Regex::new(SCRIPT_TAG_REGEX_STR).unwrap())
-}
-
-pub fn style_tag_regex() -> &'static Regex {
-    static RE: OnceLock<Regex> = OnceLock::new();
-    RE.get_or_init(|| Regex::new(STYLE_TAG_REGEX_STR).unwrap())
-}
+// Files
+pub const TEMP_FILE_SUFFIX: &str = ".cclean-tmp";
+pub const CACHE_FILE_NAME: &str = ".cclean-cache";
+pub const CONFIG_FILE_NAME: &str = ".cclean.toml";
+pub const BACKUP_SUFFIX: &str = ".bak";
+pub const BACKUP_DIR_NAME: &str = ".cclean-backup";
+pub const MANIFEST_FILE_NAME: &str = ".cclean-manifest.toml";
+pub const GIT_DIR_NAME: &str = ".git";
+/// Read in this order, so `.ignore` rules override `.gitignore` ones.
+pub const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".ignore"];
+pub const STDIN_PATH: &str = "-";
+pub const STDIN_NAME: &str = "<stdin>";
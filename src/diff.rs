@@ -0,0 +1,111 @@
+use similar::TextDiff;
+use std::env;
+use std::io::Write;
+use std::path::{Component, Path};
+
+/// Lines of unchanged context around each change, as in `git diff`.
+const CONTEXT_LINES: usize = 3;
+
+/// Unified diff of `original` against `cleaned` in the format `git apply`
+/// accepts, or an empty buffer if they are equal. Works on bytes, so the
+/// patch of a file in a legacy encoding applies to that file as it is.
+pub fn unified(path: &Path, original: &[u8], cleaned: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if original == cleaned {
+        return out;
+    }
+
+    let name = patch_path(path).unwrap_or_else(|| joined(path.components()));
+    let diff = TextDiff::from_lines(original, cleaned);
+    let mut unified = diff.unified_diff();
+    unified.context_radius(CONTEXT_LINES);
+
+    // Writes into a Vec cannot fail.
+    let _ = writeln!(out, "diff --git a/{} b/{}", name, name);
+    let _ = writeln!(out, "--- a/{}", name);
+    let _ = writeln!(out, "+++ b/{}", name);
+    for hunk in unified.iter_hunks() {
+        let _ = hunk.to_writer(&mut out);
+    }
+    out
+}
+
+/// `path` relative to the current directory with forward slashes, which is
+/// how `git apply` run from that directory expects it, or `None` if it lies
+/// outside the current directory and so cannot be named in a patch.
+pub fn patch_path(path: &Path) -> Option<String> {
+    let relative = if path.is_absolute() {
+        let cwd = env::current_dir().ok()?;
+        match path.strip_prefix(&cwd) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => path
+                .strip_prefix(cwd.canonicalize().ok()?)
+                .ok()?
+                .to_path_buf(),
+        }
+    } else {
+        path.to_path_buf()
+    };
+    let components = relative.components().filter(|c| *c != Component::CurDir);
+    if components
+        .clone()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(joined(components))
+}
+
+/// The normal components of a path joined with forward slashes.
+fn joined<'a>(components: impl Iterator<Item = Component<'a>>) -> String {
+    components
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let original = b"int a; // one\nint b;\n/* two */\nint c;";
+        let cleaned = b"int a;\nint b;\nint c;";
+        let patch = unified(Path::new("./src/a.c"), original, cleaned);
+        assert_eq!(
+            String::from_utf8(patch).unwrap(),
+            "diff --git a/src/a.c b/src/a.c\n\
+             --- a/src/a.c\n\
+             +++ b/src/a.c\n\
+             @@ -1,4 +1,3 @@\n\
+             -int a; // one\n\
+             +int a;\n \
+             int b;\n\
+             -/* two */\n \
+             int c;\n\
+             \\ No newline at end of file\n"
+        );
+        assert!(unified(Path::new("a.c"), cleaned, cleaned).is_empty());
+    }
+
+    #[test]
+    fn test_patch_path() {
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(
+            patch_path(Path::new("./src/a.c")).as_deref(),
+            Some("src/a.c")
+        );
+        assert_eq!(
+            patch_path(&cwd.join("src").join("a.c")).as_deref(),
+            Some("src/a.c")
+        );
+        assert_eq!(patch_path(Path::new("../a.c")), None);
+        if let Some(parent) = cwd.parent() {
+            assert_eq!(patch_path(&parent.join("elsewhere.c")), None);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use crate::constants;
+use chardetng::EncodingDetector;
+use encoding_rs::{
+    Decoder, DecoderResult, Encoder, EncoderResult, Encoding, UTF_16BE, UTF_16LE, UTF_8,
+};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str;
+
+/// Bytes looked at when guessing the encoding of a file without a BOM.
+pub const SAMPLE_SIZE: usize = 64 * 1024;
+const BUFFER_SIZE: usize = 16 * 1024;
+const BINARY_SAMPLE_SIZE: usize = 8 * 1024;
+/// A file is binary if more than one byte in this many is a control character.
+const BINARY_CONTROL_RATIO: usize = 10;
+
+/// Character encoding of a source file, and whether it starts with a BOM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceEncoding {
+    pub encoding: &'static Encoding,
+    pub bom: bool,
+}
+
+impl SourceEncoding {
+    pub const UTF_8: SourceEncoding = SourceEncoding {
+        encoding: UTF_8,
+        bom: false,
+    };
+
+    /// Picks the encoding of a file from its first bytes: a BOM wins, then
+    /// `forced`, then UTF-16 if most code units have a zero byte, then valid
+    /// UTF-8, then a guess.
+    pub fn detect(sample: &[u8], eof: bool, forced: Option<&'static Encoding>) -> Self {
+        if let Some((encoding, _)) = Encoding::for_bom(sample) {
+            return SourceEncoding {
+                encoding,
+                bom: true,
+            };
+        }
+        let encoding = forced
+            .or_else(|| utf16_without_bom(sample))
+            .or_else(|| is_utf8(sample, eof).then_some(UTF_8))
+            .unwrap_or_else(|| {
+                let mut detector = EncodingDetector::new();
+                detector.feed(sample, eof);
+                detector.guess(None, true)
+            });
+        SourceEncoding {
+            encoding,
+            bom: false,
+        }
+    }
+
+    /// Plain UTF-8 is passed through without decoding.
+    pub fn is_plain_utf8(&self) -> bool {
+        *self == Self::UTF_8
+    }
+
+    fn bom_bytes(&self) -> &'static [u8] {
+        match (self.bom, self.encoding) {
+            (false, _) => b"",
+            (true, e) if e == UTF_16LE => b"\xFF\xFE",
+            (true, e) if e == UTF_16BE => b"\xFE\xFF",
+            (true, _) => b"\xEF\xBB\xBF",
+        }
+    }
+}
+
+impl fmt::Display for SourceEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.encoding.name())?;
+        if self.bom {
+            f.write_str(" with BOM")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a file looks like binary data rather than text in `encoding`:
+/// it has NUL bytes, or too many control characters, in its first few KB.
+/// UTF-16 text is full of NULs, so only its control characters count.
+pub fn is_binary(sample: &[u8], encoding: SourceEncoding) -> bool {
+    let sample = &sample[..sample.len().min(BINARY_SAMPLE_SIZE)];
+    if encoding.encoding == UTF_16LE || encoding.encoding == UTF_16BE {
+        let units: Vec<u16> = sample[encoding.bom_bytes().len()..]
+            .chunks_exact(2)
+            .map(|pair| match encoding.encoding == UTF_16LE {
+                true => u16::from_le_bytes([pair[0], pair[1]]),
+                false => u16::from_be_bytes([pair[0], pair[1]]),
+            })
+            .collect();
+        let controls = units
+            .iter()
+            .filter(|&&unit| unit < 0x80 && is_control(unit as u8))
+            .count();
+        return controls * BINARY_CONTROL_RATIO > units.len();
+    }
+
+    let controls = sample.iter().filter(|&&b| is_control(b)).count();
+    sample.contains(&0) || controls * BINARY_CONTROL_RATIO > sample.len()
+}
+
+/// Control characters that do not turn up in source code, unlike tabs, line
+/// breaks, form feeds and escape.
+fn is_control(byte: u8) -> bool {
+    matches!(byte, 0x00..=0x08 | 0x0E..=0x1A | 0x1C..=0x1F | 0x7F)
+}
+
+/// Parses an `--encoding` value such as `shift_jis`, `latin1` or `utf-16le`.
+pub fn parse_label(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("{} `{}`", constants::ERR_UNKNOWN_ENCODING, label))
+}
+
+fn is_utf8(sample: &[u8], eof: bool) -> bool {
+    match str::from_utf8(sample) {
+        Ok(_) => true,
+        Err(e) => !eof && e.error_len().is_none(),
+    }
+}
+
+fn utf16_without_bom(sample: &[u8]) -> Option<&'static Encoding> {
+    let units = sample.len() / 2;
+    if units == 0 {
+        return None;
+    }
+    let zeros = |parity| {
+        sample
+            .chunks_exact(2)
+            .filter(|pair| pair[parity] == 0)
+            .count()
+    };
+    let (even, odd) = (zeros(0), zeros(1));
+    if odd * 2 > units && even * 10 < units {
+        Some(UTF_16LE)
+    } else if even * 2 > units && odd * 10 < units {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+fn malformed(encoding: SourceEncoding) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} {}", constants::ERR_DECODE, encoding.encoding.name()),
+    )
+}
+
+/// Decodes `reader` to UTF-8, skipping the BOM. Malformed input is an error
+/// rather than being replaced, so that writing the file back is lossless.
+pub struct DecodeReader<R> {
+    inner: R,
+    encoding: SourceEncoding,
+    decoder: Decoder,
+    decoded: Vec<u8>,
+    pos: usize,
+    len: usize,
+    done: bool,
+}
+
+impl<R: BufRead> DecodeReader<R> {
+    pub fn new(mut inner: R, encoding: SourceEncoding) -> Self {
+        inner.consume(encoding.bom_bytes().len());
+        DecodeReader {
+            inner,
+            encoding,
+            decoder: encoding.encoding.new_decoder_without_bom_handling(),
+            decoded: vec![0; BUFFER_SIZE],
+            pos: 0,
+            len: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> io::Read for DecodeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.len {
+            if self.done {
+                return Ok(0);
+            }
+            let input = self.inner.fill_buf()?;
+            let last = input.is_empty();
+            let (result, read, written) =
+                self.decoder
+                    .decode_to_utf8_without_replacement(input, &mut self.decoded, last);
+            self.inner.consume(read);
+            self.pos = 0;
+            self.len = written;
+            match result {
+                DecoderResult::Malformed(..) => return Err(malformed(self.encoding)),
+                DecoderResult::InputEmpty if last => self.done = true,
+                _ => {}
+            }
+        }
+
+        let n = buf.len().min(self.len - self.pos);
+        buf[..n].copy_from_slice(&self.decoded[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Decodes a whole file read into memory.
+pub fn decode(bytes: &[u8], encoding: SourceEncoding) -> io::Result<String> {
+    let mut text = String::new();
+    io::Read::read_to_string(&mut DecodeReader::new(bytes, encoding), &mut text)?;
+    Ok(text)
+}
+
+/// Encodes UTF-8 written to it back into `encoding`, starting with its BOM.
+/// Call [`EncodeWriter::finish`] once everything has been written.
+pub struct EncodeWriter<W: Write> {
+    inner: W,
+    encoding: SourceEncoding,
+    encoder: Encoder,
+    /// Trailing bytes of a character split across two writes.
+    partial: Vec<u8>,
+    encoded: Vec<u8>,
+}
+
+impl<W: Write> EncodeWriter<W> {
+    pub fn new(mut inner: W, encoding: SourceEncoding) -> io::Result<Self> {
+        inner.write_all(encoding.bom_bytes())?;
+        Ok(EncodeWriter {
+            inner,
+            encoding,
+            encoder: encoding.encoding.new_encoder(),
+            partial: Vec::new(),
+            encoded: vec![0; BUFFER_SIZE],
+        })
+    }
+
+    fn encode(&mut self, text: &str, last: bool) -> io::Result<()> {
+        let encoding = self.encoding.encoding;
+        if encoding == UTF_16LE || encoding == UTF_16BE {
+            // encoding_rs only decodes UTF-16; its encoder would produce UTF-8.
+            let bytes: Vec<u8> = text
+                .encode_utf16()
+                .flat_map(|unit| {
+                    if encoding == UTF_16LE {
+                        unit.to_le_bytes()
+                    } else {
+                        unit.to_be_bytes()
+                    }
+                })
+                .collect();
+            return self.inner.write_all(&bytes);
+        }
+
+        let mut text = text;
+        loop {
+            let (result, read, written) =
+                self.encoder
+                    .encode_from_utf8_without_replacement(text, &mut self.encoded, last);
+            self.inner.write_all(&self.encoded[..written])?;
+            text = &text[read..];
+            match result {
+                EncoderResult::InputEmpty => return Ok(()),
+                EncoderResult::OutputFull => {}
+                EncoderResult::Unmappable(c) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} `{}` {}", constants::ERR_ENCODE, c, encoding.name()),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Writes out any encoder state and flushes the inner writer.
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.partial.is_empty() {
+            return Err(malformed(SourceEncoding::UTF_8));
+        }
+        self.encode("", true)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for EncodeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        let partial = std::mem::take(&mut self.partial);
+        let valid = match str::from_utf8(&partial) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(malformed(SourceEncoding::UTF_8)),
+        };
+        self.encode(str::from_utf8(&partial[..valid]).unwrap(), false)?;
+        self.partial = partial[valid..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cleaner::CleanOptions;
+    use crate::language::Language;
+    use crate::stream::clean_stream;
+
+    fn round_trip(bytes: &[u8], forced: Option<&'static Encoding>) -> (SourceEncoding, Vec<u8>) {
+        let encoding = SourceEncoding::detect(bytes, true, forced);
+        let mut output = Vec::new();
+        let mut writer = EncodeWriter::new(&mut output, encoding).unwrap();
+        clean_stream(
+            DecodeReader::new(bytes, encoding),
+            &mut writer,
+            Language::C,
+            &CleanOptions::default(),
+        )
+        .unwrap();
+        writer.finish().unwrap();
+        (encoding, output)
+    }
+
+    #[test]
+    fn test_legacy_encodings_round_trip() {
+        // "s = \"café\"; // déjà vu" in Latin-1.
+        let (encoding, output) = round_trip(b"s = \"caf\xe9\"; // d\xe9j\xe0 vu\n", None);
+        assert_eq!(encoding.encoding, encoding_rs::WINDOWS_1252);
+        assert_eq!(output, b"s = \"caf\xe9\"; \n");
+
+        let (source, _, _) = encoding_rs::SHIFT_JIS.encode("s = \"日本語\"; /* コメント */\n");
+        let (encoding, output) = round_trip(&source, Some(encoding_rs::SHIFT_JIS));
+        assert_eq!(encoding.encoding, encoding_rs::SHIFT_JIS);
+        assert_eq!(
+            output,
+            &*encoding_rs::SHIFT_JIS.encode("s = \"日本語\"; \n").0
+        );
+    }
+
+    #[test]
+    fn test_binary_files_are_detected() {
+        let detect = |bytes: &[u8]| is_binary(bytes, SourceEncoding::detect(bytes, true, None));
+        assert!(detect(b"\x7fELF\x02\x01\x01\x00\x00\x00"));
+        assert!(detect(b"var a=1;\x00\x00var b=2;"));
+        assert!(detect(b"\x89PNG\r\n\x1a\n\x01\x02\x03\x04\x05\x06"));
+        assert!(!detect(b"int main() {\n\treturn 0;\n}\n"));
+        assert!(!detect(b"Print \"Caf\xe9\"\n"));
+
+        let utf16: Vec<u8> = "int x;\n"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        assert!(!detect(&utf16));
+    }
+
+    #[test]
+    fn test_bom_is_preserved() {
+        let utf16: Vec<u8> = b"\xFF\xFE"
+            .iter()
+            .copied()
+            .chain(
+                "int x; // \u{e9}\n"
+                    .encode_utf16()
+                    .flat_map(u16::to_le_bytes),
+            )
+            .collect();
+        let (encoding, output) = round_trip(&utf16, None);
+        assert_eq!(
+            encoding,
+            SourceEncoding {
+                encoding: UTF_16LE,
+                bom: true
+            }
+        );
+        let expected: Vec<u8> = b"\xFF\xFE"
+            .iter()
+            .copied()
+            .chain("int x; \n".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        assert_eq!(output, expected);
+
+        let (encoding, output) = round_trip(b"\xEF\xBB\xBFint x; /* c */\n", None);
+        assert!(encoding.bom);
+        assert_eq!(output, b"\xEF\xBB\xBFint x; \n");
+    }
+}
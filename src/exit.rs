@@ -0,0 +1,73 @@
+use std::fmt;
+use std::process::ExitCode;
+
+/// Exit statuses of `cclean`, as documented in the README.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Success = 0,
+    /// Some files could not be processed, or the run could not complete.
+    Failure = 1,
+    /// Invalid arguments, also used by clap for command-line errors.
+    Usage = 2,
+    /// `--check` found files that cleaning would change.
+    CheckFailed = 3,
+}
+
+impl Exit {
+    /// The status for `err`: the one carried by an [`ExitError`] in its
+    /// chain, otherwise [`Exit::Failure`].
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(|e| e.downcast_ref::<ExitError>())
+            .map_or(Exit::Failure, |e| e.exit)
+    }
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(exit as u8)
+    }
+}
+
+/// An error that ends the run with a status other than [`Exit::Failure`].
+#[derive(Debug)]
+pub struct ExitError {
+    exit: Exit,
+    message: String,
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ExitError {}
+
+pub fn usage(message: impl fmt::Display) -> anyhow::Error {
+    anyhow::Error::new(ExitError {
+        exit: Exit::Usage,
+        message: message.to_string(),
+    })
+}
+
+pub fn check_failed(message: impl fmt::Display) -> anyhow::Error {
+    anyhow::Error::new(ExitError {
+        exit: Exit::CheckFailed,
+        message: message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_of_error() {
+        assert_eq!(Exit::of(&anyhow::anyhow!("io")), Exit::Failure);
+        assert_eq!(Exit::of(&usage("bad flag")), Exit::Usage);
+        let wrapped = Err::<(), _>(check_failed("2 files")).context("outer");
+        assert_eq!(Exit::of(&wrapped.unwrap_err()), Exit::CheckFailed);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::constants;
+use crate::exit;
+use crate::Cli;
+use anyhow::Result;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A file or directory to process, after glob patterns have been expanded.
+pub struct Input {
+    pub path: PathBuf,
+    /// Directory that output paths under `--output` are made relative to.
+    /// `None` means `--output` names the output file itself.
+    pub base: Option<PathBuf>,
+    /// Named on the command line rather than matched by a pattern.
+    pub explicit: bool,
+}
+
+/// Expands the command-line paths. Paths that exist are taken as they are;
+/// anything else containing glob syntax is matched against the filesystem.
+pub fn expand(paths: &[PathBuf]) -> Result<Vec<Input>> {
+    let mut inputs = Vec::new();
+
+    for path in paths {
+        if path.exists() || !is_glob(path.as_os_str().to_string_lossy().as_ref()) {
+            let base = if path.is_dir() {
+                Some(path.clone())
+            } else if paths.len() == 1 {
+                None
+            } else {
+                Some(parent_dir(path))
+            };
+            inputs.push(Input {
+                path: path.clone(),
+                base,
+                explicit: true,
+            });
+        } else {
+            expand_glob(path, &mut inputs)?;
+        }
+    }
+
+    Ok(inputs)
+}
+
+fn expand_glob(pattern: &Path, inputs: &mut Vec<Input>) -> Result<()> {
+    // Split `src/app/**/*.ts` into the literal directory to walk and the
+    // pattern to match below it.
+    let mut base = PathBuf::new();
+    let mut rest = PathBuf::new();
+    for component in pattern.components() {
+        let literal = matches!(component, Component::Normal(c) if !is_glob(&c.to_string_lossy()));
+        if rest.as_os_str().is_empty() && (literal || !matches!(component, Component::Normal(_))) {
+            base.push(component);
+        } else {
+            rest.push(component);
+        }
+    }
+
+    let rest = rest.to_string_lossy().into_owned();
+    let matcher = GlobBuilder::new(&rest)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| {
+            exit::usage(format!(
+                "{} {}: {}",
+                constants::ERR_INVALID_PATTERN,
+                rest,
+                e
+            ))
+        })?
+        .compile_matcher();
+    let depth = if rest.contains("**") {
+        usize::MAX
+    } else {
+        Path::new(&rest).components().count()
+    };
+
+    let root = if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base.clone()
+    };
+    let mut matches: Vec<PathBuf> = WalkDir::new(&root)
+        .min_depth(1)
+        .max_depth(depth)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let relative = entry.path().strip_prefix(&root).ok()?;
+            matcher.is_match(relative).then(|| base.join(relative))
+        })
+        .collect();
+    if matches.is_empty() {
+        return Err(exit::usage(format!(
+            "{} {}",
+            constants::ERR_NO_MATCH,
+            pattern.display()
+        )));
+    }
+    matches.sort();
+
+    inputs.extend(matches.into_iter().map(|path| Input {
+        path,
+        base: Some(root.clone()),
+        explicit: false,
+    }));
+    Ok(())
+}
+
+/// `--exclude` and `--include` patterns. A pattern matches a path relative to
+/// the directory or pattern it was found under, or just its file name.
+pub struct Filter {
+    exclude: GlobSet,
+    include: Option<GlobSet>,
+}
+
+impl Filter {
+    pub fn new(cli: &Cli) -> Result<Self> {
+        let include = if cli.include.is_empty() {
+            None
+        } else {
+            Some(glob_set(&cli.include)?)
+        };
+        Ok(Filter {
+            exclude: glob_set(&cli.exclude)?,
+            include,
+        })
+    }
+
+    pub fn excludes(&self, relative: &Path) -> bool {
+        matches_path_or_name(&self.exclude, relative)
+    }
+
+    /// Only applies to files; directories are always descended into.
+    pub fn includes(&self, relative: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|set| matches_path_or_name(set, relative))
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| {
+            exit::usage(format!(
+                "{} {}: {}",
+                constants::ERR_INVALID_PATTERN,
+                pattern,
+                e
+            ))
+        })?);
+    }
+    Ok(builder.build()?)
+}
+
+fn matches_path_or_name(set: &GlobSet, relative: &Path) -> bool {
+    set.is_match(relative) || relative.file_name().is_some_and(|name| set.is_match(name))
+}
+
+fn is_glob(text: &str) -> bool {
+    text.contains(['*', '?', '[', '{'])
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_glob_expansion() {
+        let root = std::env::temp_dir().join(format!("cclean-inputs-{}", std::process::id()));
+        fs::create_dir_all(root.join("src/deep")).unwrap();
+        for file in ["src/a.ts", "src/b.js", "src/deep/c.ts"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let found = |pattern: &str| -> Vec<PathBuf> {
+            expand(&[root.join(pattern)])
+                .unwrap()
+                .into_iter()
+                .map(|input| input.path.strip_prefix(&root).unwrap().to_path_buf())
+                .collect()
+        };
+        assert_eq!(found("src/*.ts"), vec![PathBuf::from("src/a.ts")]);
+        assert_eq!(
+            found("src/**/*.ts"),
+            vec![PathBuf::from("src/a.ts"), PathBuf::from("src/deep/c.ts")]
+        );
+        assert_eq!(found("src/{b.js,deep}").len(), 2);
+        assert!(expand(&[root.join("src/*.rs")]).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_exclude_and_include_filters() {
+        use clap::Parser;
+
+        let cli = Cli::parse_from([
+            "cclean",
+            ".",
+            "--exclude",
+            "vendor",
+            "--exclude",
+            "*.min.js",
+            "--include",
+            "*.js",
+        ]);
+        let filter = Filter::new(&cli).unwrap();
+        assert!(filter.excludes(Path::new("vendor")));
+        assert!(filter.excludes(Path::new("lib/app.min.js")));
+        assert!(!filter.excludes(Path::new("lib/app.js")));
+        assert!(filter.includes(Path::new("lib/app.js")));
+        assert!(!filter.includes(Path::new("lib/app.ts")));
+
+        let filter = Filter::new(&Cli::parse_from(["cclean", "."])).unwrap();
+        assert!(filter.includes(Path::new("lib/app.ts")));
+    }
+}
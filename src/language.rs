@@ -1,36 +1,58 @@
+use crate::registry::{self, CommentSyntax};
+use std::fmt;
 use std::path::Path;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum Language {
-    C,
-    Cpp,
-    Java,
-    JavaScript,
-    TypeScript,
-    Python,
-    Html,
-    Css,
-    Php,
-    Rust,
-    Basic,
+/// Handle to a language in the global registry.
+///
+/// The built-in languages are available as associated constants; languages
+/// added with [`registry::register_language`] get a fresh handle.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Language(pub(crate) usize);
+
+// Named like the enum variants they replaced so existing callers keep compiling.
+#[allow(non_upper_case_globals)]
+impl Language {
+    pub const C: Language = Language(0);
+    pub const Cpp: Language = Language(1);
+    pub const Java: Language = Language(2);
+    pub const JavaScript: Language = Language(3);
+    pub const TypeScript: Language = Language(4);
+    pub const Python: Language = Language(5);
+    pub const Html: Language = Language(6);
+    pub const Css: Language = Language(7);
+    pub const Php: Language = Language(8);
+    pub const Rust: Language = Language(9);
+    pub const Basic: Language = Language(10);
+}
+
+/// Lines at each end of a file searched for a Vim modeline, as with Vim's
+/// default `modelines` setting.
+const VIM_MODELINE_LINES: usize = 5;
+
+/// What [`Language::from_content`] recognized a language by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentRule {
+    /// The interpreter on a `#!` line, e.g. `#!/usr/bin/env python3`.
+    Shebang,
+    /// A Vim modeline such as `vim: ft=python`.
+    VimModeline,
+    /// An Emacs mode line such as `-*- mode: c++ -*-`.
+    EmacsModeline,
+}
+
+impl fmt::Display for ContentRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ContentRule::Shebang => "shebang",
+            ContentRule::VimModeline => "vim modeline",
+            ContentRule::EmacsModeline => "emacs modeline",
+        })
+    }
 }
 
 impl Language {
     pub fn from_extension(ext: &str) -> Option<Self> {
-        match ext.to_lowercase().as_str() {
-            "c" | "h" => Some(Language::C),
-            "cpp" | "cc" | "cxx" | "hpp" | "hxx" => Some(Language::Cpp),
-            "java" => Some(Language::Java),
-            "js" | "jsx" => Some(Language::JavaScript),
-            "ts" | "tsx" => Some(Language::TypeScript),
-            "py" | "pyw" => Some(Language::Python),
-            "html" | "htm" => Some(Language::Html),
-            "css" => Some(Language::Css),
-            "php" => Some(Language::Php),
-            "rs" => Some(Language::Rust),
-            "vb" | "bas" | "vba" | "vbs" => Some(Language::Basic),
-            _ => None,
-        }
+        registry::find_by_extension(ext)
     }
 
     pub fn from_path(path: &Path) -> Option<Self> {
@@ -39,36 +61,155 @@ impl Language {
             .and_then(Self::from_extension)
     }
 
-    pub fn from_str(s: &str) -> Option<Self> {
-        match s.to_lowercase().as_str() {
-            "c" => Some(Language::C),
-            "cpp" | "c++" => Some(Language::Cpp),
-            "java" => Some(Language::Java),
-            "js" | "javascript" => Some(Language::JavaScript),
-            "ts" | "typescript" => Some(Language::TypeScript),
-            "python" | "py" => Some(Language::Python),
-            "html" => Some(Language::Html),
-            "css" => Some(Language::Css),
-            "php" => Some(Language::Php),
-            "rust" | "rs" => Some(Language::Rust),
-            "basic" | "vb" | "vba" | "vbs" => Some(Language::Basic),
-            _ => None,
-        }
+    pub fn from_name(s: &str) -> Option<Self> {
+        registry::find_by_alias(s)
     }
 
-    pub fn name(&self) -> &str {
-        match self {
-            Language::C => "C",
-            Language::Cpp => "C++",
-            Language::Java => "Java",
-            Language::JavaScript => "JavaScript",
-            Language::TypeScript => "TypeScript",
-            Language::Python => "Python",
-            Language::Html => "HTML",
-            Language::Css => "CSS",
-            Language::Php => "PHP",
-            Language::Rust => "Rust",
-            Language::Basic => "Basic",
+    /// Detects the language of a file without a known extension from its
+    /// `#!` line, an Emacs mode line on one of its first two lines, or a Vim
+    /// modeline near its start or end.
+    pub fn from_content(content: &str) -> Option<(Self, ContentRule)> {
+        let lines: Vec<&str> = content.lines().collect();
+        if let Some(language) = lines.first().and_then(|line| from_shebang(line)) {
+            return Some((language, ContentRule::Shebang));
+        }
+        if let Some(language) = lines
+            .iter()
+            .take(2)
+            .find_map(|line| from_emacs_modeline(line))
+        {
+            return Some((language, ContentRule::EmacsModeline));
         }
+        let tail = lines.len().saturating_sub(VIM_MODELINE_LINES);
+        lines
+            .iter()
+            .take(VIM_MODELINE_LINES)
+            .chain(lines.iter().skip(tail.max(VIM_MODELINE_LINES)))
+            .find_map(|line| from_vim_modeline(line))
+            .map(|language| (language, ContentRule::VimModeline))
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.syntax().name()
+    }
+
+    pub fn syntax(&self) -> &'static dyn CommentSyntax {
+        registry::syntax(*self)
+    }
+
+    /// Every registered language, built-ins first.
+    pub fn all() -> Vec<Language> {
+        (0..registry::len()).map(Language).collect()
+    }
+}
+
+/// `#!/usr/bin/python3` and `#!/usr/bin/env -S python3 -u` both name
+/// `python3`. A version suffix such as in `python3.12` is dropped if the full
+/// name is not registered.
+fn from_shebang(line: &str) -> Option<Language> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let program = |word: &str| word.rsplit('/').next().unwrap_or(word).to_string();
+    let mut interpreter = program(words.next()?);
+    if interpreter == "env" {
+        // Skip options such as `-S` and variable assignments.
+        interpreter = program(words.find(|w| !w.starts_with('-') && !w.contains('='))?);
+    }
+    registry::find_by_interpreter(&interpreter).or_else(|| {
+        registry::find_by_interpreter(
+            interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'),
+        )
+    })
+}
+
+/// `-*- mode: c++ -*-`, possibly among other variables, or just `-*- c++ -*-`.
+fn from_emacs_modeline(line: &str) -> Option<Language> {
+    let (_, rest) = line.split_once("-*-")?;
+    let (variables, _) = rest.split_once("-*-")?;
+    let mode = if variables.contains(':') {
+        variables.split(';').find_map(|variable| {
+            let (name, value) = variable.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("mode").then_some(value)
+        })?
+    } else {
+        variables
+    };
+    let mode = mode.trim();
+    Language::from_name(mode.strip_suffix("-mode").unwrap_or(mode))
+}
+
+/// `vim: ft=python` or `vim: set filetype=python :`, also after `vi:` or
+/// `ex:`. The marker must start the line or follow whitespace.
+fn from_vim_modeline(line: &str) -> Option<Language> {
+    let options = ["vim:", "vi:", "ex:"].iter().find_map(|marker| {
+        line.match_indices(marker)
+            .find(|&(i, _)| i == 0 || line[..i].ends_with(char::is_whitespace))
+            .map(|(i, _)| &line[i + marker.len()..])
+    })?;
+    options
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .find_map(|option| {
+            let (name, value) = option.split_once('=')?;
+            matches!(name, "ft" | "filetype" | "syn" | "syntax").then_some(value)
+        })
+        .and_then(Language::from_name)
+}
+
+impl fmt::Debug for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_content() {
+        let detect = |content: &str| Language::from_content(content);
+        assert_eq!(
+            detect("#!/usr/bin/env python3\nprint(1)\n"),
+            Some((Language::Python, ContentRule::Shebang))
+        );
+        assert_eq!(
+            detect("#!/usr/bin/python3.12 -u\n"),
+            Some((Language::Python, ContentRule::Shebang))
+        );
+        assert_eq!(
+            detect("#!/usr/bin/env -S node --no-warnings\n"),
+            Some((Language::JavaScript, ContentRule::Shebang))
+        );
+        assert_eq!(
+            detect("#!/usr/local/bin/php\n<?php echo 1;\n"),
+            Some((Language::Php, ContentRule::Shebang))
+        );
+        assert_eq!(
+            detect("#!/bin/sh\n# -*- mode: c++; tab-width: 4 -*-\n"),
+            Some((Language::Cpp, ContentRule::EmacsModeline))
+        );
+        assert_eq!(
+            detect("/* -*- C -*- */\nint x;\n"),
+            Some((Language::C, ContentRule::EmacsModeline))
+        );
+        let tail = format!("{}# vim: set ft=python ts=4 :\n", "x = 1\n".repeat(20));
+        assert_eq!(
+            detect(&tail),
+            Some((Language::Python, ContentRule::VimModeline))
+        );
+        assert_eq!(
+            detect("// vi:filetype=rust\nfn main() {}\n"),
+            Some((Language::Rust, ContentRule::VimModeline))
+        );
+
+        assert_eq!(detect("#!/bin/sh\necho hi\n"), None);
+        assert_eq!(detect("index: ft=python\n"), None);
+        let middle = format!("{}# vim: ft=python\n{}", "a\n".repeat(10), "b\n".repeat(10));
+        assert_eq!(detect(&middle), None);
     }
 }
@@ -0,0 +1,560 @@
+use crate::language::Language;
+use crate::registry::CommentSyntax;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommentKind {
+    Line,
+    Block,
+    Doc,
+}
+
+impl CommentKind {
+    pub fn name(&self) -> &str {
+        match self {
+            CommentKind::Line => "line",
+            CommentKind::Block => "block",
+            CommentKind::Doc => "doc",
+        }
+    }
+}
+
+/// A single comment found in a source file.
+///
+/// `range` is a byte range into the lexed content; `line` and `column` are
+/// 1-based and point at the first character of the comment marker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommentSpan {
+    pub range: Range<usize>,
+    pub line: usize,
+    pub column: usize,
+    pub kind: CommentKind,
+    pub language: Language,
+}
+
+impl CommentSpan {
+    pub fn text<'a>(&self, content: &'a str) -> &'a str {
+        &content[self.range.clone()]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagnosticKind {
+    UnterminatedComment,
+    UnterminatedString,
+}
+
+impl DiagnosticKind {
+    pub fn description(&self) -> &str {
+        match self {
+            DiagnosticKind::UnterminatedComment => "unterminated block comment",
+            DiagnosticKind::UnterminatedString => "unterminated string literal",
+        }
+    }
+}
+
+/// A construct the lexer opened but never saw closed, which usually means it
+/// swallowed more of the input than intended.
+///
+/// `offset` is the byte offset of the opening delimiter; `line` and `column`
+/// are 1-based, with the column counted in characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub language: Language,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} ({})",
+            self.line,
+            self.column,
+            self.kind.description(),
+            self.language
+        )
+    }
+}
+
+/// Resumable lexer used to clean input that arrives in chunks.
+///
+/// `feed` lexes `buf`, which starts at byte `base` of the whole input, and
+/// returns how many bytes of it were consumed. It may stop short when a
+/// delimiter could straddle the end of `buf`; the caller then passes the
+/// unconsumed tail again with more input appended. Once `eof` is set the
+/// whole buffer is consumed and `finish` closes any comment still open.
+pub trait StreamLexer {
+    fn feed(&mut self, buf: &str, base: usize, eof: bool, spans: &mut Vec<CommentSpan>) -> usize;
+
+    fn finish(&mut self, end: usize, spans: &mut Vec<CommentSpan>);
+
+    /// Start of a comment that is open but not yet closed. Text from this
+    /// offset on must not be written out until the comment is resolved.
+    fn pending_start(&self) -> Option<usize>;
+
+    /// Unterminated strings and comments found so far. Constructs still open
+    /// at the end of input are only reported once `finish` has run.
+    fn diagnostics(&self) -> &[Diagnostic] {
+        &[]
+    }
+}
+
+/// Fallback [`StreamLexer`] for syntaxes that can only lex complete input:
+/// it consumes nothing until end of input and then lexes everything at once.
+pub struct BufferedLexer<'a, S: ?Sized> {
+    syntax: &'a S,
+    language: Language,
+}
+
+impl<'a, S: CommentSyntax + ?Sized> BufferedLexer<'a, S> {
+    pub fn new(syntax: &'a S, language: Language) -> Self {
+        Self { syntax, language }
+    }
+}
+
+impl<S: CommentSyntax + ?Sized> StreamLexer for BufferedLexer<'_, S> {
+    fn feed(&mut self, buf: &str, base: usize, eof: bool, spans: &mut Vec<CommentSpan>) -> usize {
+        if !eof {
+            return 0;
+        }
+        let first = spans.len();
+        self.syntax.lex(buf, self.language, spans);
+        for span in &mut spans[first..] {
+            span.range = span.range.start + base..span.range.end + base;
+        }
+        buf.len()
+    }
+
+    fn finish(&mut self, _end: usize, _spans: &mut Vec<CommentSpan>) {}
+
+    fn pending_start(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Returns every comment in `content`, ordered by position.
+pub fn lex_comments(content: &str, language: Language) -> Vec<CommentSpan> {
+    let mut spans = Vec::new();
+    language.syntax().lex(content, language, &mut spans);
+    let shebang = shebang_len(content);
+    spans.retain(|span| span.range.end > shebang);
+    locate(content, &mut spans);
+    spans
+}
+
+/// Length of the `#!` line at the start of `content`, if any. It is kept
+/// even in languages where `#` starts a comment, since removing it would
+/// break the script. Rust's `#![...]` attributes are code, not shebangs.
+pub(crate) fn shebang_len(content: &str) -> usize {
+    if !content.starts_with("#!") || content.starts_with("#![") {
+        return 0;
+    }
+    content.find('\n').unwrap_or(content.len())
+}
+
+/// Returns every string or block comment in `content` that is never closed.
+pub fn find_unterminated(content: &str, language: Language) -> Vec<Diagnostic> {
+    let mut spans = Vec::new();
+    let mut lexer = language.syntax().stream_lexer(language);
+    lexer.feed(content, 0, true, &mut spans);
+    lexer.finish(content.len(), &mut spans);
+    lexer.diagnostics().to_vec()
+}
+
+pub(crate) fn push_span(
+    spans: &mut Vec<CommentSpan>,
+    range: Range<usize>,
+    kind: CommentKind,
+    language: Language,
+) {
+    spans.push(CommentSpan {
+        range,
+        line: 0,
+        column: 0,
+        kind,
+        language,
+    });
+}
+
+fn locate(content: &str, spans: &mut [CommentSpan]) {
+    let mut pos = 0;
+    let mut line = 1;
+    let mut line_start = 0;
+
+    for span in spans.iter_mut() {
+        for (i, b) in content.as_bytes()[pos..span.range.start].iter().enumerate() {
+            if *b == b'\n' {
+                line += 1;
+                line_start = pos + i + 1;
+            }
+        }
+        pos = span.range.start;
+        span.line = line;
+        span.column = content[line_start..pos].chars().count() + 1;
+    }
+}
+
+/// Line and column of a lexer's position, advanced over each consumed step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cursor {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+impl Cursor {
+    pub(crate) fn new() -> Self {
+        Self { line: 1, column: 1 }
+    }
+
+    pub(crate) fn advance(&mut self, text: &[u8]) {
+        for &b in text {
+            if b == b'\n' {
+                self.line += 1;
+                self.column = 1;
+            } else if b & 0xC0 != 0x80 {
+                self.column += 1;
+            }
+        }
+    }
+
+    pub(crate) fn diagnostic(
+        &self,
+        kind: DiagnosticKind,
+        offset: usize,
+        language: Language,
+    ) -> Diagnostic {
+        Diagnostic {
+            kind,
+            offset,
+            line: self.line,
+            column: self.column,
+            language,
+        }
+    }
+}
+
+const HTML_COMMENT_OPEN: &[u8] = b"<!--";
+const HTML_COMMENT_CLOSE: &[u8] = b"-->";
+const HTML_LOOKAHEAD: usize = 8;
+
+enum HtmlState {
+    Text,
+    Comment {
+        start: usize,
+        opened: Cursor,
+    },
+    OpenTag {
+        language: Language,
+        close: &'static [u8],
+    },
+    Embedded {
+        close: &'static [u8],
+        lexer: Box<dyn StreamLexer>,
+        opened: Cursor,
+    },
+}
+
+/// Lexes HTML comments and hands `<script>` and `<style>` bodies to the
+/// JavaScript and CSS lexers.
+pub(crate) struct HtmlLexer {
+    language: Language,
+    state: HtmlState,
+    cursor: Cursor,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl HtmlLexer {
+    pub(crate) fn new(language: Language) -> Self {
+        Self {
+            language,
+            state: HtmlState::Text,
+            cursor: Cursor::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Moves the diagnostics of a finished embedded lexer over, translating
+    /// positions that are relative to the start of the embedded body.
+    fn take_embedded_diagnostics(&mut self, lexer: &dyn StreamLexer, opened: Cursor) {
+        for diagnostic in lexer.diagnostics() {
+            let mut diagnostic = diagnostic.clone();
+            if diagnostic.line == 1 {
+                diagnostic.column += opened.column - 1;
+            }
+            diagnostic.line += opened.line - 1;
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn step_embedded(
+        &mut self,
+        buf: &str,
+        i: usize,
+        base: usize,
+        eof: bool,
+        spans: &mut Vec<CommentSpan>,
+    ) -> Option<usize> {
+        let HtmlState::Embedded { close, lexer, .. } = &mut self.state else {
+            return Some(0);
+        };
+        let rest = &buf.as_bytes()[i..];
+
+        match find(rest, close) {
+            Some(p) => {
+                let consumed = lexer.feed(&buf[i..i + p], base + i, true, spans);
+                lexer.finish(base + i + consumed, spans);
+                self.end_embedded();
+                Some(consumed)
+            }
+            None if eof => {
+                let consumed = lexer.feed(&buf[i..], base + i, true, spans);
+                lexer.finish(base + buf.len(), spans);
+                self.end_embedded();
+                Some(consumed)
+            }
+            None => {
+                let mut safe = buf.len().saturating_sub(close.len() - 1).max(i);
+                while !buf.is_char_boundary(safe) {
+                    safe -= 1;
+                }
+                let consumed = lexer.feed(&buf[i..safe], base + i, false, spans);
+                (consumed > 0).then_some(consumed)
+            }
+        }
+    }
+
+    fn end_embedded(&mut self) {
+        if let HtmlState::Embedded { lexer, opened, .. } =
+            std::mem::replace(&mut self.state, HtmlState::Text)
+        {
+            self.take_embedded_diagnostics(lexer.as_ref(), opened);
+        }
+    }
+}
+
+impl StreamLexer for HtmlLexer {
+    fn feed(&mut self, buf: &str, base: usize, eof: bool, spans: &mut Vec<CommentSpan>) -> usize {
+        let bytes = buf.as_bytes();
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            let step = match self.state {
+                HtmlState::Text => {
+                    if !eof && rest.len() < HTML_LOOKAHEAD {
+                        break;
+                    }
+                    if rest.starts_with(HTML_COMMENT_OPEN) {
+                        self.state = HtmlState::Comment {
+                            start: base + i,
+                            opened: self.cursor,
+                        };
+                        HTML_COMMENT_OPEN.len()
+                    } else if rest.starts_with(b"<script") {
+                        self.state = HtmlState::OpenTag {
+                            language: Language::JavaScript,
+                            close: b"</script>",
+                        };
+                        b"<script".len()
+                    } else if rest.starts_with(b"<style") {
+                        self.state = HtmlState::OpenTag {
+                            language: Language::Css,
+                            close: b"</style>",
+                        };
+                        b"<style".len()
+                    } else {
+                        1
+                    }
+                }
+                HtmlState::Comment { start, .. } => match find(rest, HTML_COMMENT_CLOSE) {
+                    Some(p) => {
+                        let end = base + i + p + HTML_COMMENT_CLOSE.len();
+                        push_span(spans, start..end, CommentKind::Block, self.language);
+                        self.state = HtmlState::Text;
+                        p + HTML_COMMENT_CLOSE.len()
+                    }
+                    None if eof => rest.len(),
+                    None if rest.len() < HTML_COMMENT_CLOSE.len() => break,
+                    None => rest.len() + 1 - HTML_COMMENT_CLOSE.len(),
+                },
+                HtmlState::OpenTag { language, close } => {
+                    match rest.iter().position(|&b| b == b'>') {
+                        Some(p) => {
+                            let mut opened = self.cursor;
+                            opened.advance(&rest[..=p]);
+                            self.state = HtmlState::Embedded {
+                                close,
+                                lexer: language.syntax().stream_lexer(language),
+                                opened,
+                            };
+                            p + 1
+                        }
+                        None => rest.len(),
+                    }
+                }
+                HtmlState::Embedded { .. } => match self.step_embedded(buf, i, base, eof, spans) {
+                    Some(step) => step,
+                    None => break,
+                },
+            };
+
+            let mut next = i + step;
+            while !buf.is_char_boundary(next) {
+                next += 1;
+            }
+            self.cursor.advance(&bytes[i..next]);
+            i = next;
+        }
+
+        i
+    }
+
+    fn finish(&mut self, end: usize, spans: &mut Vec<CommentSpan>) {
+        match &mut self.state {
+            HtmlState::Comment { start, opened } => {
+                push_span(spans, *start..end, CommentKind::Block, self.language);
+                let diagnostic =
+                    opened.diagnostic(DiagnosticKind::UnterminatedComment, *start, self.language);
+                self.diagnostics.push(diagnostic);
+            }
+            HtmlState::Embedded { lexer, .. } => {
+                lexer.finish(end, spans);
+                self.end_embedded();
+            }
+            _ => {}
+        }
+        self.state = HtmlState::Text;
+    }
+
+    fn pending_start(&self) -> Option<usize> {
+        match &self.state {
+            HtmlState::Comment { start, .. } => Some(*start),
+            HtmlState::Embedded { lexer, .. } => lexer.pending_start(),
+            _ => None,
+        }
+    }
+
+    fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(content: &str, language: Language) -> Vec<(String, CommentKind)> {
+        lex_comments(content, language)
+            .iter()
+            .map(|s| (s.text(content).to_string(), s.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_span_positions() {
+        let input = "int x;\n  /* a */ int y; // b\n";
+        let spans = lex_comments(input, Language::C);
+        assert_eq!(spans.len(), 2);
+        assert_eq!((spans[0].line, spans[0].column), (2, 3));
+        assert_eq!(spans[0].text(input), "/* a */");
+        assert_eq!((spans[1].line, spans[1].column), (2, 18));
+        assert_eq!(spans[1].text(input), "// b");
+    }
+
+    #[test]
+    fn test_column_counts_characters() {
+        let input = "s = \"héllo\" # note";
+        let spans = lex_comments(input, Language::Python);
+        assert_eq!(spans[0].column, 13);
+    }
+
+    #[test]
+    fn test_doc_comment_kinds() {
+        let input = "/// doc\n//// rule\n//! inner\n/** doc */\n/**/\n/* block */\n// line";
+        let found: Vec<CommentKind> = kinds(input, Language::Rust)
+            .into_iter()
+            .map(|(_, k)| k)
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                CommentKind::Doc,
+                CommentKind::Line,
+                CommentKind::Doc,
+                CommentKind::Doc,
+                CommentKind::Block,
+                CommentKind::Block,
+                CommentKind::Line,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_html_embedded_languages() {
+        let input = "<!-- top -->\n<script>\nvar s = '<!-- x -->'; // js\n</script>\n<style>/* css */</style>";
+        let spans = lex_comments(input, Language::Html);
+        let found: Vec<(&str, Language)> =
+            spans.iter().map(|s| (s.text(input), s.language)).collect();
+        assert_eq!(
+            found,
+            vec![
+                ("<!-- top -->", Language::Html),
+                ("// js", Language::JavaScript),
+                ("/* css */", Language::Css),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_constructs() {
+        let found = |input: &str, language| -> Vec<(DiagnosticKind, usize, usize)> {
+            find_unterminated(input, language)
+                .iter()
+                .map(|d| (d.kind, d.line, d.column))
+                .collect()
+        };
+
+        assert_eq!(found("int a = 1; /* ok */\n", Language::C), vec![]);
+        assert_eq!(
+            found("char *s = \"open;\nint b; // \"x\n", Language::C),
+            vec![(DiagnosticKind::UnterminatedString, 1, 11)]
+        );
+        assert_eq!(
+            found("x = 1\ns = \"\"\"doc\n", Language::Python),
+            vec![(DiagnosticKind::UnterminatedString, 2, 5)]
+        );
+        assert_eq!(
+            found(
+                "<p>\n  <script>let a;\n /* x\n</script><!-- y",
+                Language::Html
+            ),
+            vec![
+                (DiagnosticKind::UnterminatedComment, 3, 2),
+                (DiagnosticKind::UnterminatedComment, 4, 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_basic_rem_needs_word_boundary() {
+        let input = "PREM x\nREM comment\nx = 1 ' note";
+        assert_eq!(
+            kinds(input, Language::Basic),
+            vec![
+                ("REM comment".to_string(), CommentKind::Line),
+                ("' note".to_string(), CommentKind::Line),
+            ]
+        );
+    }
+}
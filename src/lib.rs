@@ -0,0 +1,32 @@
+//! Comment removal for C/C++, Java, JavaScript, TypeScript, Python, HTML, CSS,
+//! PHP, Rust and Basic sources.
+//!
+//! ```
+//! use comments_cleaner::{clean_comments, Language};
+//!
+//! let cleaned = clean_comments("int x = 5; // answer\n", Language::C);
+//! assert_eq!(cleaned, "int x = 5; \n");
+//! ```
+//!
+//! The `cclean` command-line tool is built on top of this crate and lives
+//! behind the default `cli` feature. Depend on the crate with
+//! `default-features = false` to pull in the library only.
+
+pub mod cleaner;
+pub mod language;
+pub mod lexer;
+pub mod registry;
+pub mod spec;
+pub mod stream;
+pub mod verify;
+
+pub use cleaner::{clean_comments, clean_comments_with, CleanOptions};
+pub use language::{ContentRule, Language};
+pub use lexer::{
+    find_unterminated, lex_comments, CommentKind, CommentSpan, Diagnostic, DiagnosticKind,
+    StreamLexer,
+};
+pub use registry::{register_language, CommentSyntax};
+pub use spec::{register_spec_file, LanguageSpec, SpecSyntax};
+pub use stream::{clean_stream, CleanReport};
+pub use verify::{verify_clean, Mismatch};
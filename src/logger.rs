@@ -1,8 +1,266 @@
+use clap::ValueEnum;
 use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::env;
+use std::fs::File;
+use std::io::{self, IsTerminal, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+static CONSOLE: Mutex<()> = Mutex::new(());
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+/// Set while files are processed with a progress bar on the terminal.
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+/// File records of a `--format json` run, printed with the summary.
+static RECORDS: Mutex<Vec<FileRecord>> = Mutex::new(Vec::new());
+
+/// How the run is reported on stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Colored text for people.
+    #[default]
+    Text,
+    /// A single JSON document once the run is over.
+    Json,
+    /// One JSON object per line as each file is done.
+    Ndjson,
+}
+
+/// How much of the text log reaches the terminal. Each level includes the
+/// ones before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Warnings and errors only.
+    Quiet,
+    #[default]
+    Normal,
+    /// Per-file details and ignored paths (`-v`).
+    Verbose,
+    /// Configuration and scheduling details (`-vv`).
+    Debug,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Default)]
+struct Settings {
+    format: Format,
+    verbosity: Verbosity,
+    /// Receives every message at every level, without colors.
+    log_file: Option<Mutex<LineWriter<File>>>,
+    /// Set when stdout carries cleaned source, as when reading stdin; every
+    /// message then goes to stderr.
+    stdout_reserved: bool,
+}
+
+/// Configures output for the rest of the run; only the first call has an
+/// effect.
+pub fn init(
+    format: Format,
+    verbosity: Verbosity,
+    color: ColorChoice,
+    log_file: Option<&Path>,
+    stdout_reserved: bool,
+) -> io::Result<()> {
+    let terminal = if stdout_reserved {
+        io::stderr().is_terminal()
+    } else {
+        io::stdout().is_terminal()
+    };
+    let colorize = match color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => terminal && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+    };
+    colored::control::set_override(colorize);
+
+    let log_file = match log_file {
+        Some(path) => Some(Mutex::new(LineWriter::new(File::create(path)?))),
+        None => None,
+    };
+    let _ = SETTINGS.set(Settings {
+        format,
+        verbosity,
+        log_file,
+        stdout_reserved,
+    });
+    Ok(())
+}
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+fn format() -> Format {
+    settings().format
+}
+
+/// Writes one message to the log file, if any, and to the terminal if
+/// `level` is enabled. Stdout only carries the text log in text format;
+/// messages meant for stderr are shown in every format.
+fn emit(level: Verbosity, stderr: bool, message: &str) {
+    let settings = settings();
+    if let Some(ref file) = settings.log_file {
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(file, "{}", strip_ansi(message));
+    }
+    if level > settings.verbosity {
+        return;
+    }
+    let stderr = stderr || settings.stdout_reserved;
+    // The progress bar stands in for per-file lines on the same terminal;
+    // warnings and errors are printed above it.
+    let progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    match (progress.as_ref(), stderr) {
+        (Some(progress), true) => progress.bar.suspend(|| eprintln!("{}", message)),
+        (Some(progress), false) if progress.hides_stdout => {}
+        (None, true) => eprintln!("{}", message),
+        (_, false) if settings.format == Format::Text => println!("{}", message),
+        (_, false) => {}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Whether a progress bar is shown on stderr, and if so whether it replaces
+/// the per-file lines on stdout, which it only does when stdout is a
+/// terminal as well; a redirected stdout still gets every line.
+fn progress_layout(settings: &Settings, is_terminal: impl Fn(Stream) -> bool) -> Option<bool> {
+    (settings.format == Format::Text
+        && settings.verbosity == Verbosity::Normal
+        && is_terminal(Stream::Stderr))
+    .then(|| is_terminal(Stream::Stdout))
+}
+struct Progress {
+    bar: ProgressBar,
+    /// Set when stdout is the same terminal, so per-file lines are left out.
+    hides_stdout: bool,
+    files: usize,
+    done: usize,
+}
+
+/// Shows a progress bar on stderr for `files` files totalling `bytes`, if
+/// stderr is a terminal and the normal text log would be printed.
+pub fn start_progress(files: usize, bytes: u64) {
+    let Some(hides_stdout) = progress_layout(settings(), |stream| match stream {
+        Stream::Stdout => io::stdout().is_terminal(),
+        Stream::Stderr => io::stderr().is_terminal(),
+    }) else {
+        return;
+    };
+    let bar = ProgressBar::new(bytes).with_style(
+        ProgressStyle::with_template(crate::constants::PROGRESS_TEMPLATE)
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+            .progress_chars("=> "),
+    );
+    let progress = Progress {
+        bar,
+        hides_stdout,
+        files,
+        done: 0,
+    };
+    progress.bar.set_prefix(progress.prefix());
+    *PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) = Some(progress);
+}
+
+impl Progress {
+    fn prefix(&self) -> String {
+        format!(
+            "{}/{} {}",
+            self.done,
+            self.files,
+            crate::constants::MSG_FILES_SUFFIX
+        )
+    }
+}
+
+/// Shows `path` as the file being worked on.
+pub fn progress_file(path: &str) {
+    if let Some(ref progress) = *PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) {
+        progress.bar.set_message(path.to_string());
+    }
+}
+
+/// Counts a file of `bytes` bytes as done.
+pub fn progress_done(bytes: u64) {
+    if let Some(ref mut progress) = *PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) {
+        progress.done += 1;
+        progress.bar.set_prefix(progress.prefix());
+        progress.bar.inc(bytes);
+    }
+}
+
+pub fn finish_progress() {
+    if let Some(progress) = PROGRESS.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        progress.bar.finish_and_clear();
+    }
+}
+
+fn strip_ansi(message: &str) -> String {
+    let mut plain = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end with a byte in @..~.
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+/// A line of the normal text log.
+macro_rules! out {
+    ($($arg:tt)*) => {
+        emit(Verbosity::Normal, false, &format!($($arg)*))
+    };
+}
+
+/// A warning or error, shown even with `--quiet`.
+macro_rules! err {
+    ($($arg:tt)*) => {
+        emit(Verbosity::Quiet, true, &format!($($arg)*))
+    };
+}
+
+/// Serializes multi-line log blocks written from worker threads.
+pub fn lock() -> MutexGuard<'static, ()> {
+    CONSOLE.lock().unwrap_or_else(|e| e.into_inner())
+}
 
 pub fn print_header(name: &str, version: &str) {
-    println!("{} v{}", name.cyan().bold(), version.cyan().bold());
-    println!(
+    out!("{} v{}", name.cyan().bold(), version.cyan().bold());
+    out!(
         "{}",
         crate::constants::APP_SEPARATOR_CHAR
             .repeat(crate::constants::APP_SEPARATOR_LEN)
@@ -11,15 +269,19 @@ pub fn print_header(name: &str, version: &str) {
 }
 
 pub fn success(msg: &str) {
-    println!("{}", msg.green().bold());
+    out!("{}", msg.green().bold());
 }
 
 pub fn error_msg(msg: &str) {
-    eprintln!("{} {}", crate::constants::MSG_ERROR_PREFIX.red(), msg);
+    err!("{} {}", crate::constants::MSG_ERROR_PREFIX.red(), msg);
+}
+
+pub fn log_warning(msg: &str) {
+    err!("{} {}", crate::constants::MSG_WARNING_PREFIX.yellow(), msg);
 }
 
 pub fn log_processing(path: &str, lang_name: &str) {
-    println!(
+    out!(
         "{} {} ({})",
         crate::constants::MSG_PROCESSING_PREFIX.cyan(),
         path,
@@ -28,33 +290,428 @@ pub fn log_processing(path: &str, lang_name: &str) {
 }
 
 pub fn log_skip(path: &str) {
-    println!(
+    out!(
         "{} {}",
         crate::constants::MSG_SKIPPING_PREFIX.yellow(),
         path.dimmed()
     );
 }
 
+pub fn log_ignored(path: &str) {
+    emit(
+        Verbosity::Verbose,
+        false,
+        &format!(
+            "{} {}",
+            crate::constants::MSG_IGNORING_PREFIX.dimmed(),
+            path.dimmed()
+        ),
+    );
+}
+
+pub fn log_filtered(path: &str) {
+    emit(
+        Verbosity::Verbose,
+        false,
+        &format!(
+            "{} {}",
+            crate::constants::MSG_SKIPPING_PREFIX.dimmed(),
+            path.dimmed()
+        ),
+    );
+}
+
+pub fn log_details(comments: usize, bytes_before: u64, bytes_after: Option<u64>) {
+    let sizes = match bytes_after {
+        Some(after) => format!(", {} -> {} bytes", bytes_before, after),
+        None => String::new(),
+    };
+    emit(
+        Verbosity::Verbose,
+        false,
+        &format!(
+            "{} {}{}",
+            crate::constants::MSG_COMMENTS_REMOVED.dimmed(),
+            comments,
+            sizes
+        ),
+    );
+}
+
+pub fn debug(msg: &str) {
+    emit(
+        Verbosity::Debug,
+        false,
+        &format!("{} {}", crate::constants::MSG_DEBUG_PREFIX.dimmed(), msg),
+    );
+}
+
 pub fn log_backup(path: &str) {
-    println!("{} {}", crate::constants::MSG_BACKUP_PREFIX.green(), path);
+    out!("{} {}", crate::constants::MSG_BACKUP_PREFIX.green(), path);
 }
 
 pub fn log_output(path: &str) {
-    println!("{} {}", crate::constants::MSG_OUTPUT_PREFIX.green(), path);
+    out!("{} {}", crate::constants::MSG_OUTPUT_PREFIX.green(), path);
+}
+
+pub fn log_patch(path: &str) {
+    out!("{} {}", crate::constants::MSG_PATCH_PREFIX.green(), path);
+}
+
+pub fn log_diff(diff: &str) {
+    for line in diff.lines() {
+        if line.starts_with("diff ") || line.starts_with("--- ") || line.starts_with("+++ ") {
+            out!("{}", line.bold());
+        } else if line.starts_with("@@") {
+            out!("{}", line.cyan());
+        } else if line.starts_with('-') {
+            out!("{}", line.red());
+        } else if line.starts_with('+') {
+            out!("{}", line.green());
+        } else {
+            out!("{}", line);
+        }
+    }
+}
+
+pub fn log_committed(files: usize) {
+    out!(
+        "{} {} {}",
+        crate::constants::MSG_COMMITTED.green(),
+        files,
+        crate::constants::MSG_FILES_SUFFIX
+    );
+}
+
+pub fn log_rolled_back(files: usize) {
+    err!(
+        "{} {} {}",
+        crate::constants::MSG_ROLLED_BACK.red(),
+        files,
+        crate::constants::MSG_FILES_SUFFIX
+    );
+}
+
+pub fn log_restore_run(created: &str) {
+    out!("{} {}", crate::constants::MSG_RESTORE_RUN.cyan(), created);
+}
+
+pub fn log_restored(original: &str, backup: &str) {
+    out!(
+        "{} {} {}",
+        crate::constants::MSG_RESTORED_PREFIX.green(),
+        original,
+        format!("<- {}", backup).dimmed()
+    );
 }
 
 pub fn log_dry_run() {
-    println!("{}", crate::constants::MSG_DRY_RUN_SKIP.dimmed());
+    out!("{}", crate::constants::MSG_DRY_RUN_SKIP.dimmed());
+}
+
+pub fn log_unchanged() {
+    out!("{}", crate::constants::MSG_UNCHANGED_SKIP.dimmed());
+}
+
+pub fn log_binary() {
+    out!("{}", crate::constants::MSG_BINARY_SKIP.dimmed());
+}
+
+pub fn log_clean() {
+    out!("{}", crate::constants::MSG_CLEAN.dimmed());
+}
+
+pub fn log_would_change(comments: usize) {
+    out!(
+        "{} {}",
+        crate::constants::MSG_WOULD_CHANGE.yellow(),
+        comments
+    );
+}
+
+/// Counts printed at the end of a run.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Summary {
+    pub processed: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub binary: usize,
+    pub ignored: usize,
+    /// Files `--check` found comments in.
+    pub would_change: usize,
+    pub failed: usize,
+    /// Files left unprocessed by `--fail-fast` or Ctrl-C.
+    pub not_run: usize,
 }
 
-pub fn log_summary(processed: usize, skipped: usize) {
-    println!(
-        "\n{} {} {}, {} {} {}",
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Processed,
+    Skipped,
+    Error,
+}
+
+/// What happened to one file, as reported by `--format json` and `ndjson`.
+#[derive(Debug, Serialize)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub language: Option<&'static str>,
+    pub action: Action,
+    /// Why a file was skipped: `binary`, `unchanged` or `unsupported`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    pub comments_removed: usize,
+    pub bytes_before: Option<u64>,
+    pub bytes_after: Option<u64>,
+    pub output: Option<PathBuf>,
+    pub backup: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A line of `--format ndjson` output.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Line<'a> {
+    File(&'a FileRecord),
+    Summary(&'a Summary),
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    files: &'a [FileRecord],
+    summary: &'a Summary,
+}
+
+pub fn log_record(record: FileRecord) {
+    match format() {
+        Format::Text => {}
+        Format::Json => RECORDS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(record),
+        Format::Ndjson => print_json(&Line::File(&record)),
+    }
+}
+
+fn print_json(value: &impl Serialize) {
+    // Records only hold strings and numbers, which always serialize.
+    if let Ok(json) = serde_json::to_string(value) {
+        println!("{}", json);
+    }
+}
+
+pub fn log_summary(summary: &Summary) {
+    match format() {
+        Format::Text => {}
+        Format::Json => {
+            let mut files = std::mem::take(&mut *RECORDS.lock().unwrap_or_else(|e| e.into_inner()));
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            if let Ok(json) = serde_json::to_string_pretty(&Report {
+                files: &files,
+                summary,
+            }) {
+                println!("{}", json);
+            }
+            return;
+        }
+        Format::Ndjson => return print_json(&Line::Summary(summary)),
+    }
+
+    // Categories that are usually empty are only shown when they are not.
+    let optional = |count: usize, label: &str, suffix: &str| {
+        if count > 0 {
+            format!(", {} {} {}", label.dimmed(), count, suffix)
+        } else {
+            String::new()
+        }
+    };
+    let failed = if summary.failed > 0 {
+        format!(
+            ", {} {} {}",
+            crate::constants::MSG_FAILED_SUMMARY.red().bold(),
+            summary.failed,
+            crate::constants::MSG_FILES_SUFFIX
+        )
+    } else {
+        String::new()
+    };
+    out!(
+        "\n{} {} {}{}, {} {} {}{}{}{}{}{}",
         crate::constants::MSG_PROCESSED_SUMMARY.green(),
-        processed,
+        summary.processed,
         crate::constants::MSG_FILES_SUFFIX,
+        optional(
+            summary.unchanged,
+            crate::constants::MSG_UNCHANGED_SUMMARY,
+            crate::constants::MSG_FILES_SUFFIX
+        ),
         crate::constants::MSG_SKIPPED_SUMMARY.yellow(),
-        skipped,
-        crate::constants::MSG_FILES_SUFFIX
+        summary.skipped,
+        crate::constants::MSG_FILES_SUFFIX,
+        optional(
+            summary.binary,
+            crate::constants::MSG_BINARY_SUMMARY,
+            crate::constants::MSG_FILES_SUFFIX
+        ),
+        optional(
+            summary.ignored,
+            crate::constants::MSG_IGNORED_SUMMARY,
+            crate::constants::MSG_PATHS_SUFFIX
+        ),
+        optional(
+            summary.would_change,
+            crate::constants::MSG_WOULD_CHANGE_SUMMARY,
+            crate::constants::MSG_FILES_SUFFIX
+        ),
+        failed,
+        optional(
+            summary.not_run,
+            crate::constants::MSG_NOT_RUN_SUMMARY,
+            crate::constants::MSG_FILES_SUFFIX
+        ),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi("\x1b[32mOutput:\x1b[0m a.c"), "Output: a.c");
+        assert_eq!(strip_ansi("\x1b[1;36mv1\x1b[0m"), "v1");
+        assert_eq!(strip_ansi("plain"), "plain");
+    }
+
+    #[test]
+    fn test_verbosity_from_flags() {
+        assert_eq!(Verbosity::from_flags(true, 2), Verbosity::Quiet);
+        assert_eq!(Verbosity::from_flags(false, 0), Verbosity::Normal);
+        assert_eq!(Verbosity::from_flags(false, 1), Verbosity::Verbose);
+        assert_eq!(Verbosity::from_flags(false, 5), Verbosity::Debug);
+    }
+
+    #[test]
+    fn test_progress_layout() {
+        let terminal = |stdout: bool, stderr: bool| {
+            move |stream| match stream {
+                Stream::Stdout => stdout,
+                Stream::Stderr => stderr,
+            }
+        };
+        let normal = Settings::default();
+        assert_eq!(progress_layout(&normal, terminal(true, true)), Some(true));
+        // `cclean src > log`: the bar on the terminal, every line in the log.
+        assert_eq!(progress_layout(&normal, terminal(false, true)), Some(false));
+        assert_eq!(progress_layout(&normal, terminal(true, false)), None);
+
+        let verbose = Settings {
+            verbosity: Verbosity::Verbose,
+            ..Settings::default()
+        };
+        assert_eq!(progress_layout(&verbose, terminal(true, true)), None);
+        let json = Settings {
+            format: Format::Json,
+            ..Settings::default()
+        };
+        assert_eq!(progress_layout(&json, terminal(true, true)), None);
+    }
+
+    #[test]
+    fn test_report_schema() {
+        use crate::constants::{REASON_BINARY, REASON_UNCHANGED, REASON_UNSUPPORTED};
+        use serde_json::json;
+
+        // Dashboards parse these names and values; changing them is a breaking change.
+        assert_eq!(
+            [REASON_BINARY, REASON_UNCHANGED, REASON_UNSUPPORTED],
+            ["binary", "unchanged", "unsupported"]
+        );
+
+        let processed = FileRecord {
+            path: PathBuf::from("src/a.c"),
+            language: Some("C"),
+            action: Action::Processed,
+            reason: None,
+            comments_removed: 2,
+            bytes_before: Some(30),
+            bytes_after: Some(12),
+            output: Some(PathBuf::from("src/a.c")),
+            backup: Some(PathBuf::from("src/a.c.bak")),
+            error: None,
+        };
+        assert_eq!(
+            serde_json::to_value(Line::File(&processed)).unwrap(),
+            json!({
+                "type": "file",
+                "path": "src/a.c",
+                "language": "C",
+                "action": "processed",
+                "comments_removed": 2,
+                "bytes_before": 30,
+                "bytes_after": 12,
+                "output": "src/a.c",
+                "backup": "src/a.c.bak",
+            })
+        );
+
+        let skipped = FileRecord {
+            path: PathBuf::from("logo.png"),
+            language: None,
+            action: Action::Skipped,
+            reason: Some(REASON_UNSUPPORTED),
+            comments_removed: 0,
+            bytes_before: None,
+            bytes_after: None,
+            output: None,
+            backup: None,
+            error: None,
+        };
+        let value = serde_json::to_value(Line::File(&skipped)).unwrap();
+        assert_eq!(value["action"], "skipped");
+        assert_eq!(value["reason"], "unsupported");
+        assert!(value["language"].is_null() && value.get("error").is_none());
+
+        let failed = FileRecord {
+            action: Action::Error,
+            reason: None,
+            error: Some("Cannot read file: b.c".to_string()),
+            ..skipped
+        };
+        let value = serde_json::to_value(Line::File(&failed)).unwrap();
+        assert_eq!(value["action"], "error");
+        assert_eq!(value["error"], "Cannot read file: b.c");
+        assert!(value.get("reason").is_none());
+
+        let summary = Summary {
+            processed: 1,
+            failed: 1,
+            ..Summary::default()
+        };
+        assert_eq!(
+            serde_json::to_value(Line::Summary(&summary)).unwrap(),
+            json!({
+                "type": "summary",
+                "processed": 1,
+                "unchanged": 0,
+                "skipped": 0,
+                "binary": 0,
+                "ignored": 0,
+                "would_change": 0,
+                "failed": 1,
+                "not_run": 0,
+            })
+        );
+
+        // The JSON document lists the files before the summary.
+        let report = serde_json::to_string(&Report {
+            files: &[processed, failed],
+            summary: &summary,
+        })
+        .unwrap();
+        assert!(report.starts_with(r#"{"files":[{"path":"src/a.c""#));
+        assert!(report.find(r#""summary":"#) > report.find(r#""path":"logo.png""#));
+    }
+}
@@ -1,23 +1,41 @@
-mod cleaner;
+mod atomic;
+mod backup;
+mod cache;
+mod config;
 mod constants;
-mod language;
+mod diff;
+mod encoding;
+mod exit;
+mod inputs;
 mod logger;
 mod processor;
 
-use anyhow::Result;
-use clap::Parser;
-use std::path::PathBuf;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use comments_cleaner::{cleaner, language, lexer, spec, stream, verify};
+use exit::Exit;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 #[derive(Parser)]
 #[clap(
     name = "cclean",
     version = env!("CARGO_PKG_VERSION"),
     about = "Code comment cleaning tool - supports C/C++, Java/JavaScript/TypeScript, Python, HTML/CSS, PHP, Rust, Basic",
-    long_about = "A powerful command-line tool for automatically removing comments from code in multiple programming languages"
+    long_about = "A powerful command-line tool for automatically removing comments from code in multiple programming languages",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 pub struct Cli {
-    #[clap(value_name = "PATH", help = "File or directory path to process")]
-    pub path: PathBuf,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    #[clap(
+        value_name = "PATH",
+        required = true,
+        help = "Files, directories or glob patterns (e.g. 'src/**/*.ts') to process, or - to filter standard input to standard output"
+    )]
+    pub paths: Vec<PathBuf>,
 
     #[clap(
         short = 'o',
@@ -30,20 +48,63 @@ pub struct Cli {
     #[clap(
         short = 'r',
         long = "recursive",
-        help = "Recursively process all files in directory"
+        help = "Recursively process all files in directory",
+        overrides_with = "no_recursive"
     )]
     pub recursive: bool,
 
+    #[clap(
+        long = "no-recursive",
+        overrides_with = "recursive",
+        help = "Do not recurse into directories, even if a config file enables it"
+    )]
+    pub no_recursive: bool,
+
     #[clap(
         short = 'i',
         long = "in-place",
-        help = "Modify original file directly (no backup)"
+        help = "Modify original file directly (no backup)",
+        overrides_with = "no_in_place"
     )]
     pub in_place: bool,
 
-    #[clap(short = 'b', long = "backup", help = "Create backup file (.bak)")]
+    #[clap(
+        long = "no-in-place",
+        overrides_with = "in_place",
+        help = "Do not modify files in place, even if a config file enables it (implied by --output)"
+    )]
+    pub no_in_place: bool,
+
+    #[clap(
+        short = 'b',
+        long = "backup",
+        help = "Create backup file (.bak)",
+        overrides_with = "no_backup"
+    )]
     pub backup: bool,
 
+    #[clap(
+        long = "no-backup",
+        overrides_with = "backup",
+        help = "Do not create backups, even if a config file enables them"
+    )]
+    pub no_backup: bool,
+
+    #[clap(
+        long = "backup-mode",
+        value_name = "MODE",
+        value_enum,
+        help = "Where to keep backups: next to the file, mirrored under the backup directory, or in a per-run subdirectory of it (implies --backup)"
+    )]
+    pub backup_mode: Option<backup::BackupMode>,
+
+    #[clap(
+        long = "backup-dir",
+        value_name = "DIR",
+        help = "Backup directory for the dir and timestamp modes, also holding the manifest used by restore (default: .cclean-backup)"
+    )]
+    pub backup_dir: Option<PathBuf>,
+
     #[clap(
         short = 'l',
         long = "lang",
@@ -55,6 +116,26 @@ pub struct Cli {
     #[clap(long = "dry-run", help = "Dry run, do not actually modify files")]
     pub dry_run: bool,
 
+    #[clap(
+        long = "check",
+        conflicts_with = "dry_run",
+        help = "Write nothing; list the files cleaning would change and exit with an error if there are any"
+    )]
+    pub check: bool,
+
+    #[clap(
+        long = "diff",
+        help = "Write nothing; print a unified diff of what cleaning would change"
+    )]
+    pub diff: bool,
+
+    #[clap(
+        long = "patch",
+        value_name = "FILE",
+        help = "Write nothing but a patch of what cleaning would change, for git apply"
+    )]
+    pub patch: Option<PathBuf>,
+
     #[clap(
         short = 'e',
         long = "extensions",
@@ -62,16 +143,300 @@ pub struct Cli {
         help = "Specify file extensions to process (comma-separated, e.g.: c,cpp,h)"
     )]
     pub extensions: Option<String>,
+
+    #[clap(
+        long = "exclude",
+        value_name = "GLOB",
+        help = "Skip files and directories matching a glob, e.g. '*.min.js' or 'vendor' (repeatable)"
+    )]
+    pub exclude: Vec<String>,
+
+    #[clap(
+        long = "include",
+        value_name = "GLOB",
+        help = "Only process files matching a glob (repeatable)"
+    )]
+    pub include: Vec<String>,
+
+    #[clap(
+        long = "no-ignore",
+        help = "Do not skip files matched by .gitignore and .ignore files"
+    )]
+    pub no_ignore: bool,
+
+    #[clap(
+        long = "lang-def",
+        value_name = "FILE",
+        help = "Load a TOML language definition file, or every .toml file in a directory (repeatable)"
+    )]
+    pub lang_defs: Vec<PathBuf>,
+
+    #[clap(
+        short = 'j',
+        long = "jobs",
+        value_name = "N",
+        help = "Number of files to process in parallel in directory mode (default: number of CPUs)"
+    )]
+    pub jobs: Option<usize>,
+
+    #[clap(
+        long = "incremental",
+        help = "Skip files whose content and settings are unchanged since the last run (tracked in .cclean-cache)",
+        overrides_with = "no_incremental"
+    )]
+    pub incremental: bool,
+
+    #[clap(
+        long = "no-incremental",
+        overrides_with = "incremental",
+        help = "Process every file, even if a config file enables --incremental"
+    )]
+    pub no_incremental: bool,
+
+    #[clap(
+        long = "verify",
+        help = "Re-lex each cleaned file and refuse to write it if anything besides comments and whitespace changed",
+        overrides_with = "no_verify"
+    )]
+    pub verify: bool,
+
+    #[clap(
+        long = "no-verify",
+        overrides_with = "verify",
+        help = "Do not verify cleaned files, even if a config file enables it"
+    )]
+    pub no_verify: bool,
+
+    #[clap(
+        long = "force",
+        help = "Write files even if they contain an unterminated comment or string",
+        overrides_with = "no_force"
+    )]
+    pub force: bool,
+
+    #[clap(
+        long = "no-force",
+        overrides_with = "force",
+        help = "Refuse files with unterminated comments or strings, even if a config file sets force"
+    )]
+    pub no_force: bool,
+
+    #[clap(
+        long = "fail-fast",
+        help = "Stop starting new files after the first one that fails"
+    )]
+    pub fail_fast: bool,
+
+    #[clap(
+        long = "transactional",
+        help = "Stage every output first and only write them if all files succeed; roll back on failure or Ctrl-C",
+        overrides_with = "no_transactional"
+    )]
+    pub transactional: bool,
+
+    #[clap(
+        long = "no-transactional",
+        overrides_with = "transactional",
+        help = "Write each file as it is done, even if a config file enables --transactional"
+    )]
+    pub no_transactional: bool,
+
+    #[clap(
+        long = "preserve-mtime",
+        help = "Keep the modification time of the original file on the cleaned output",
+        overrides_with = "no_preserve_mtime"
+    )]
+    pub preserve_mtime: bool,
+
+    #[clap(
+        long = "no-preserve-mtime",
+        overrides_with = "preserve_mtime",
+        help = "Do not keep modification times, even if a config file enables it"
+    )]
+    pub no_preserve_mtime: bool,
+
+    #[clap(
+        long = "format",
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t,
+        help = "Report format on stdout: colored text, one JSON document, or one JSON object per line"
+    )]
+    pub format: logger::Format,
+
+    #[clap(
+        short = 'q',
+        long = "quiet",
+        conflicts_with = "verbose",
+        help = "Only print warnings and errors"
+    )]
+    pub quiet: bool,
+
+    #[clap(
+        short = 'v',
+        long = "verbose",
+        action = clap::ArgAction::Count,
+        help = "Print more: -v adds per-file details and ignored paths, -vv debugging information"
+    )]
+    pub verbose: u8,
+
+    #[clap(
+        long = "no-progress",
+        help = "Print a line per file instead of a progress bar when stderr is a terminal"
+    )]
+    pub no_progress: bool,
+
+    #[clap(
+        long = "color",
+        value_name = "WHEN",
+        value_enum,
+        default_value_t,
+        help = "Color output: auto (on a terminal unless NO_COLOR is set), always or never"
+    )]
+    pub color: logger::ColorChoice,
+
+    #[clap(
+        long = "log-file",
+        value_name = "FILE",
+        help = "Also write the full log, at every verbosity level and without colors, to a file"
+    )]
+    pub log_file: Option<PathBuf>,
+
+    #[clap(
+        long = "encoding",
+        value_name = "ENCODING",
+        value_parser = encoding::parse_label,
+        help = "Encoding of files without a BOM, e.g. shift_jis, latin1, utf-16le (default: detected)"
+    )]
+    pub encoding: Option<&'static encoding_rs::Encoding>,
+
+    #[clap(
+        long = "stdin-filename",
+        value_name = "PATH",
+        help = "File name used to detect the language and find .cclean.toml when reading from stdin"
+    )]
+    pub stdin_filename: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Put back the originals backed up by the last run
+    Restore {
+        #[clap(
+            long = "backup-dir",
+            value_name = "DIR",
+            help = "Backup directory holding the run manifest (default: .cclean-backup)"
+        )]
+        backup_dir: Option<PathBuf>,
+    },
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => Exit::Success.into(),
+        Err(e) => {
+            logger::error_msg(&format!("{:#}", e));
+            Exit::of(&e).into()
+        }
+    }
+}
+
+/// Registers the `--lang-def` files. One that cannot be read or parsed is
+/// a usage error, like a bad flag.
+fn load_language_definitions(cli: &Cli) -> Result<()> {
+    processor::load_language_definitions(&cli.lang_defs)
+        .map_err(|e| exit::usage(format!("{:#}", e)))
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+fn run(mut cli: Cli) -> Result<()> {
+    let mut resolver = config::Resolver::default();
+    let reads_stdin = cli
+        .paths
+        .iter()
+        .any(|p| p.as_os_str() == constants::STDIN_PATH);
+    logger::init(
+        cli.format,
+        logger::Verbosity::from_flags(cli.quiet, cli.verbose),
+        cli.color,
+        cli.log_file.as_deref(),
+        reads_stdin,
+    )
+    .with_context(|| {
+        format!(
+            "{} {}",
+            constants::ERR_LOG_FILE,
+            cli.log_file.as_deref().unwrap_or(Path::new("")).display()
+        )
+    })?;
+
+    if let Some(Command::Restore { backup_dir }) = cli.command {
+        logger::print_header(constants::APP_NAME, constants::APP_VERSION);
+        let dir = match backup_dir {
+            Some(dir) => dir,
+            None => resolver.scope_for(Path::new("."))?.backup_dir(),
+        };
+        backup::restore(&dir)?;
+        logger::success(constants::MSG_RESTORE_COMPLETED);
+        return Ok(());
+    }
+
+    if reads_stdin {
+        if cli.paths.len() > 1 {
+            return Err(exit::usage(constants::ERR_STDIN_WITH_PATHS));
+        }
+        load_language_definitions(&cli)?;
+        return processor::process_stdin(&cli, &mut resolver);
+    }
 
     logger::print_header(constants::APP_NAME, constants::APP_VERSION);
 
-    processor::process(&cli.path, &cli)?;
+    load_language_definitions(&cli)?;
+    // Run-wide keys such as `in_place` cannot differ between the inputs, or
+    // a config meant for one tree would rewrite files in another.
+    let mut existing = cli.paths.iter().filter(|p| p.exists());
+    if let Some(first) = existing.next() {
+        let scope = resolver.scope_for(first)?;
+        for path in existing {
+            if !scope.same_run_defaults(&*resolver.scope_for(path)?) {
+                return Err(exit::usage(format!(
+                    "{} {}, {}",
+                    constants::ERR_CONFIG_CONFLICT,
+                    first.display(),
+                    path.display()
+                )));
+            }
+        }
+        scope.apply_defaults(&mut cli);
+    }
+    processor::process(&cli, &mut resolver)?;
 
     logger::success(constants::MSG_PROCESSING_COMPLETED);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bad_language_definition_is_a_usage_error() {
+        let dir = std::env::temp_dir().join(format!("cclean-lang-def-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let invalid = dir.join("invalid.toml");
+        std::fs::write(&invalid, "name = \"Broken\"\nextensions = 3\n").unwrap();
+
+        for path in [dir.join("missing.toml"), invalid] {
+            let cli = Cli::parse_from(["cclean", "--lang-def", path.to_str().unwrap(), "a.c"]);
+            let err = load_language_definitions(&cli).unwrap_err();
+            assert_eq!(Exit::of(&err), Exit::Usage, "{:#}", err);
+            assert!(
+                format!("{:#}", err).contains(path.to_str().unwrap()),
+                "{:#}",
+                err
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,117 +1,978 @@
-use crate::cleaner;
+use crate::atomic::{AtomicFile, Transaction};
+use crate::backup::Backups;
+use crate::cache::{self, Cache};
+use crate::config::{FileSettings, Resolver, Scope};
 use crate::constants;
-use crate::language::Language;
-use crate::logger;
+use crate::diff;
+use crate::encoding::{self, DecodeReader, EncodeWriter, SourceEncoding};
+use crate::exit;
+use crate::inputs::{self, Filter, Input};
+use crate::language::{ContentRule, Language};
+use crate::lexer::Diagnostic;
+use crate::logger::{self, Action, FileRecord, Summary};
+use crate::spec;
+use crate::stream::{clean_stream, CleanReport};
+use crate::verify::verify_clean;
 use crate::Cli;
 use anyhow::{Context, Result};
-use std::fs;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use walkdir::WalkDir;
 
-pub fn process(path: &Path, cli: &Cli) -> Result<()> {
-    if path.is_file() {
-        process_file(path, cli)?;
-    } else if path.is_dir() {
-        process_directory(path, cli)?;
-    } else {
-        anyhow::bail!("{} {}", constants::ERR_PATH_NOT_EXIST, path.display());
+/// Bytes read from each end of an extensionless file to look for a `#!`
+/// line or an editor modeline.
+const DETECT_SAMPLE_SIZE: u64 = 4 * 1024;
+
+pub fn load_language_definitions(paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        if path.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+                .collect();
+            files.sort();
+            for file in files {
+                spec::register_spec_file(&file)?;
+            }
+        } else {
+            spec::register_spec_file(path)?;
+        }
     }
     Ok(())
 }
 
-fn process_directory(dir: &Path, cli: &Cli) -> Result<()> {
-    let extensions: Option<Vec<String>> = cli
-        .extensions
-        .as_ref()
-        .map(|exts| exts.split(',').map(|s| s.trim().to_string()).collect());
+struct Job {
+    path: PathBuf,
+    base: Option<PathBuf>,
+    language: Language,
+    /// Set when the language was detected from the content.
+    rule: Option<ContentRule>,
+    settings: FileSettings,
+    /// Size when collected, for the progress bar.
+    size: u64,
+}
 
-    let walker = if cli.recursive {
-        WalkDir::new(dir).into_iter()
-    } else {
-        WalkDir::new(dir).max_depth(1).into_iter()
-    };
+/// Files gathered from the command line before any of them is processed.
+#[derive(Default)]
+struct Batch {
+    jobs: Vec<Job>,
+    seen: HashSet<PathBuf>,
+    /// Files with an unknown language or a filtered-out extension.
+    skipped: usize,
+    /// Paths left out by ignore files or exclude patterns.
+    ignored: usize,
+}
 
-    let mut processed_count = 0;
-    let mut skipped_count = 0;
+impl Batch {
+    fn skip(&mut self, path: &Path) {
+        self.skipped += 1;
+        logger::log_record(FileRecord {
+            path: path.to_path_buf(),
+            language: None,
+            action: Action::Skipped,
+            reason: Some(constants::REASON_UNSUPPORTED),
+            comments_removed: 0,
+            bytes_before: None,
+            bytes_after: None,
+            output: None,
+            backup: None,
+            error: None,
+        });
+    }
+}
 
-    for entry in walker.filter_map(|e| e.ok()) {
-        let path = entry.path();
+/// State shared by every file of a run.
+struct Run<'a> {
+    cli: &'a Cli,
+    cache: Option<Cache>,
+    backups: Backups,
+    /// Set with `--transactional`; outputs are staged here instead of written.
+    transaction: Option<Transaction>,
+    /// Set on Ctrl-C during a transactional run.
+    interrupted: Arc<AtomicBool>,
+    /// Set on the first failure; with `--fail-fast` no file is started after it.
+    failed: AtomicBool,
+}
 
-        if !path.is_file() {
-            continue;
-        }
+/// What became of one job.
+enum Done {
+    Report(FileReport),
+    Failed,
+    /// Not started after Ctrl-C, or after a failure with `--fail-fast`.
+    NotRun,
+}
 
-        if let Some(ref exts) = extensions {
-            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                if !exts.contains(&ext.to_string()) {
-                    skipped_count += 1;
-                    continue;
+/// Reports results in job order as workers finish them, holding back any
+/// that finish before an earlier job, so the output is the same whichever
+/// worker is faster.
+#[derive(Default)]
+struct InOrder {
+    /// Results finished out of order, by job index; `None` for a job that
+    /// was not run.
+    ready: BTreeMap<usize, Option<Result<FileReport>>>,
+    /// Every job up to the first unfinished one.
+    done: Vec<Done>,
+}
+
+impl InOrder {
+    fn add(
+        &mut self,
+        index: usize,
+        result: Option<Result<FileReport>>,
+        jobs: &[Job],
+        emit: &Emit<'_>,
+    ) {
+        self.ready.insert(index, result);
+        while let Some(result) = self.ready.remove(&self.done.len()) {
+            let done = match result {
+                None => Done::NotRun,
+                Some(result) => {
+                    emit(&jobs[self.done.len()], &result);
+                    match result {
+                        Ok(report) => Done::Report(report),
+                        Err(_) => Done::Failed,
+                    }
                 }
+            };
+            self.done.push(done);
+        }
+    }
+}
+
+/// Reports the result of one job; [`log_result`] outside of tests.
+type Emit<'a> = dyn Fn(&Job, &Result<FileReport>) + Sync + 'a;
+
+/// Processes every file and directory on the command line as one batch, with
+/// a single summary at the end.
+pub fn process(cli: &Cli, resolver: &mut Resolver) -> Result<()> {
+    process_with(cli, resolver, &log_result).map(|_| ())
+}
+
+/// [`process`] reporting each file through `emit`, returning the summary.
+fn process_with(cli: &Cli, resolver: &mut Resolver, emit: &Emit<'_>) -> Result<Summary> {
+    if let Some(ref lang_str) = cli.language {
+        if Language::from_name(lang_str).is_none() {
+            return Err(exit::usage(constants::ERR_UNRECOGNIZED_LANG_CLI));
+        }
+    }
+    let inputs = inputs::expand(&cli.paths)?;
+    let writes = !cli.dry_run && !is_preview(cli);
+    let cache = (cli.incremental && writes).then(|| {
+        let path = cache_path(&inputs, cli);
+        logger::debug(&format!("{} {}", constants::MSG_CACHE_FILE, path.display()));
+        Cache::load(path)
+    });
+
+    let filter = Filter::new(cli)?;
+    let mut batch = Batch::default();
+    for input in &inputs {
+        if input.path.is_dir() {
+            collect_directory(input, cli, &filter, resolver, &mut batch)?;
+        } else if input.path.is_file() {
+            collect_file(input, cli, &filter, resolver, &mut batch)?;
+        } else {
+            return Err(exit::usage(format!(
+                "{} {}",
+                constants::ERR_PATH_NOT_EXIST,
+                input.path.display()
+            )));
+        }
+    }
+
+    // Processed and reported in path order, whatever order the inputs were
+    // found in.
+    batch.jobs.sort_by(|a, b| a.path.cmp(&b.path));
+    if !cli.in_place {
+        check_output_paths(&batch, cli)?;
+    }
+
+    let run = Run {
+        cli,
+        cache,
+        backups: Backups::new(
+            cli.backup_dir
+                .as_deref()
+                .unwrap_or(Path::new(constants::BACKUP_DIR_NAME)),
+        )?,
+        transaction: (cli.transactional && writes).then(Transaction::default),
+        interrupted: Arc::new(AtomicBool::new(false)),
+        failed: AtomicBool::new(false),
+    };
+    if run.transaction.is_some() {
+        // Ctrl-C must not stop the process halfway through a commit, but a
+        // second one still does, in case the run is stuck. The shutdown hook
+        // is registered first so that it sees the flag before the first
+        // signal sets it.
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(
+                signal,
+                exit::Exit::Failure as i32,
+                Arc::clone(&run.interrupted),
+            )?;
+            signal_hook::flag::register(signal, Arc::clone(&run.interrupted))?;
+        }
+    }
+
+    let workers = worker_count(cli);
+    logger::debug(&format!("{} {}", constants::MSG_WORKERS, workers));
+    let pool = ThreadPoolBuilder::new().num_threads(workers).build()?;
+    // Per-file output is what --check and --diff are run for.
+    if !cli.no_progress && !cli.check && !cli.diff {
+        logger::start_progress(
+            batch.jobs.len(),
+            batch.jobs.iter().map(|job| job.size).sum(),
+        );
+    }
+    let ordered = Mutex::new(InOrder::default());
+    pool.install(|| {
+        batch.jobs.par_iter().enumerate().for_each(|(index, job)| {
+            let result = if run.interrupted.load(Ordering::SeqCst)
+                || (cli.fail_fast && run.failed.load(Ordering::SeqCst))
+            {
+                None
             } else {
-                skipped_count += 1;
+                logger::progress_file(&job.path.display().to_string());
+                let result = process_single_file(job, &run);
+                if result.is_err() {
+                    run.failed.store(true, Ordering::SeqCst);
+                }
+                logger::progress_done(job.size);
+                Some(result)
+            };
+            ordered
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .add(index, result, &batch.jobs, emit);
+        })
+    });
+    logger::finish_progress();
+    let results = ordered.into_inner().unwrap_or_else(|e| e.into_inner()).done;
+
+    let mut summary = Summary {
+        skipped: batch.skipped,
+        ignored: batch.ignored,
+        ..Summary::default()
+    };
+    let mut patch = Vec::new();
+    for result in results {
+        let report = match result {
+            Done::Report(report) => report,
+            Done::Failed => {
+                summary.failed += 1;
+                continue;
+            }
+            Done::NotRun => {
+                summary.not_run += 1;
                 continue;
             }
+        };
+        match report.outcome {
+            Outcome::Cleaned | Outcome::Clean => summary.processed += 1,
+            Outcome::Unchanged => summary.unchanged += 1,
+            Outcome::Binary => summary.binary += 1,
+            Outcome::WouldChange => {
+                summary.processed += 1;
+                summary.would_change += 1;
+            }
+        }
+        if let Some(part) = report.patch {
+            patch.extend(part);
         }
+    }
+    logger::log_summary(&summary);
+    let failed_count = summary.failed;
 
-        let language = if let Some(ref lang_str) = cli.language {
-            Language::from_str(lang_str)
+    if let Some(transaction) = run.transaction {
+        let staged = transaction.len();
+        let result = if run.interrupted.load(Ordering::SeqCst) {
+            Err(anyhow::anyhow!(constants::ERR_INTERRUPTED))
+        } else if failed_count > 0 {
+            Err(anyhow::anyhow!(
+                "{} {}",
+                failed_count,
+                constants::ERR_FILES_FAILED
+            ))
+        } else {
+            transaction
+                .commit(&run.interrupted)
+                .context(constants::ERR_COMMIT_FAILED)
+        };
+        // A transaction dropped without committing removes its staged files.
+        if let Err(e) = result {
+            run.backups.discard();
+            logger::log_rolled_back(staged);
+            return Err(e);
+        }
+        logger::log_committed(staged);
+    }
+
+    if let Some(ref path) = cli.patch {
+        write_patch(path, &patch)?;
+        logger::log_patch(&path.display().to_string());
+    }
+    if let Some(cache) = run.cache {
+        // Only a run that went through every file under its directories can
+        // tell which entries are stale.
+        let whole_tree = cli.recursive
+            && summary.not_run == 0
+            && !run.interrupted.load(Ordering::SeqCst)
+            && inputs.iter().all(|input| input.path.is_dir());
+        cache.save(whole_tree)?;
+    }
+    run.backups.save_manifest()?;
+    if failed_count > 0 && summary.not_run > 0 {
+        anyhow::bail!(
+            "{} {}, {} {}",
+            failed_count,
+            constants::ERR_FILES_FAILED,
+            summary.not_run,
+            constants::ERR_NOT_RUN
+        );
+    }
+    if failed_count > 0 {
+        anyhow::bail!("{} {}", failed_count, constants::ERR_FILES_FAILED);
+    }
+    if cli.check && summary.would_change > 0 {
+        return Err(exit::check_failed(format!(
+            "{} {}",
+            summary.would_change,
+            constants::ERR_CHECK_FAILED
+        )));
+    }
+    Ok(summary)
+}
+
+fn write_patch(path: &Path, patch: &[u8]) -> Result<()> {
+    let error = || format!("{} {}", constants::ERR_WRITE_FILE, path.display());
+    let file = AtomicFile::create(path).with_context(error)?;
+    io::Write::write_all(&mut file.file(), patch).with_context(error)?;
+    file.commit(None, false).with_context(error)?;
+    Ok(())
+}
+
+/// The cache lives in the output directory when one is given, otherwise next
+/// to the first input.
+fn cache_path(inputs: &[Input], cli: &Cli) -> PathBuf {
+    let first = &inputs[0];
+    let dir = match cli.output {
+        Some(ref output) if !cli.in_place && first.base.is_some() => output.clone(),
+        _ if first.path.is_dir() => first.path.clone(),
+        _ => first
+            .path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf(),
+    };
+    dir.join(constants::CACHE_FILE_NAME)
+}
+
+/// Cleans standard input to standard output. Nothing but the cleaned source
+/// is written to stdout, and nothing at all if the input is refused.
+pub fn process_stdin(cli: &Cli, resolver: &mut Resolver) -> Result<()> {
+    process_stdin_with(cli, resolver, io::stdin().lock(), io::stdout().lock())
+}
+
+/// [`process_stdin`] reading `input` and writing `output` instead.
+fn process_stdin_with(
+    cli: &Cli,
+    resolver: &mut Resolver,
+    mut reader: impl Read,
+    mut output: impl Write,
+) -> Result<()> {
+    let hint = cli.stdin_filename.as_deref();
+    let source = hint.unwrap_or(Path::new(constants::STDIN_NAME));
+    let scope = match hint.and_then(Path::parent).filter(|dir| dir.is_dir()) {
+        Some(dir) => resolver.scope_for(dir)?,
+        None => resolver.scope_for(Path::new("."))?,
+    };
+
+    let language = match cli.language {
+        Some(ref lang_str) => Some(
+            Language::from_name(lang_str)
+                .ok_or_else(|| exit::usage(constants::ERR_UNRECOGNIZED_LANG_CLI))?,
+        ),
+        None => hint.and_then(|hint| scope.language_for(hint)),
+    };
+
+    let read_error = || format!("{} {}", constants::ERR_READ_FILE, source.display());
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).with_context(read_error)?;
+    let language = match language {
+        Some(language) => language,
+        None => match Language::from_content(&String::from_utf8_lossy(&bytes)) {
+            Some((language, _)) => language,
+            None if hint.is_some() => {
+                return Err(exit::usage(constants::ERR_UNRECOGNIZED_LANG_EXT))
+            }
+            None => return Err(exit::usage(constants::ERR_STDIN_LANGUAGE)),
+        },
+    };
+    let settings = scope.file_settings(cli, language);
+    let encoding = SourceEncoding::detect(&bytes, true, settings.encoding);
+    if encoding::is_binary(&bytes, encoding) {
+        logger::log_warning(&format!(
+            "{}: {}",
+            source.display(),
+            constants::MSG_BINARY_PASSTHROUGH
+        ));
+        output.write_all(&bytes)?;
+        output.flush()?;
+        return Ok(());
+    }
+    let input = encoding::decode(&bytes, encoding).with_context(read_error)?;
+
+    let mut cleaned = Vec::new();
+    let report = clean_stream(input.as_bytes(), &mut cleaned, language, &settings.options)?;
+    check_diagnostics(source, &report, settings.force)?;
+    if settings.verify {
+        verify_text(source, &input, &String::from_utf8_lossy(&cleaned), language)?;
+    }
+    for diagnostic in &report.diagnostics {
+        logger::log_warning(&format!(
+            "{}:{}:{}: {}",
+            source.display(),
+            diagnostic.line,
+            diagnostic.column,
+            diagnostic.kind.description()
+        ));
+    }
+
+    if let Some(ref path) = cli.patch {
+        let mut encoded = Vec::new();
+        let mut writer = EncodeWriter::new(&mut encoded, encoding)?;
+        writer.write_all(&cleaned)?;
+        writer.finish()?;
+        let patch = if diff::patch_path(source).is_some() {
+            diff::unified(source, &bytes, &encoded)
         } else {
-            Language::from_path(path)
+            logger::log_warning(&format!(
+                "{}: {}",
+                source.display(),
+                constants::MSG_OUTSIDE_PATCH
+            ));
+            Vec::new()
         };
+        write_patch(path, &patch)?;
+    }
+    if cli.diff {
+        output.write_all(&diff::unified(source, input.as_bytes(), &cleaned))?;
+        output.flush()?;
+    }
+    if cli.check && cleaned != input.as_bytes() {
+        return Err(exit::check_failed(format!(
+            "1 {}",
+            constants::ERR_CHECK_FAILED
+        )));
+    }
+    if is_preview(cli) {
+        return Ok(());
+    }
+
+    let mut writer = EncodeWriter::new(&mut output, encoding)?;
+    writer.write_all(&cleaned)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Adds the files under `input` to the batch.
+fn collect_directory(
+    input: &Input,
+    cli: &Cli,
+    filter: &Filter,
+    resolver: &mut Resolver,
+    batch: &mut Batch,
+) -> Result<()> {
+    let dir = &input.path;
+    let root = fs::canonicalize(dir)?;
+    let mut walker = if cli.recursive {
+        WalkDir::new(dir).into_iter()
+    } else {
+        WalkDir::new(dir).max_depth(1).into_iter()
+    };
 
-        if language.is_none() {
-            logger::log_skip(&path.display().to_string());
-            skipped_count += 1;
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let path = entry.path();
+        if entry.depth() == 0 {
+            continue;
+        }
+        let is_dir = entry.file_type().is_dir();
+        if is_dir
+            && ((!cli.no_ignore && entry.file_name() == constants::GIT_DIR_NAME)
+                || path.join(constants::MANIFEST_FILE_NAME).exists())
+        {
+            // Repository metadata and our own backup directories.
+            walker.skip_current_dir();
             continue;
         }
 
-        match process_single_file(path, Some(dir), cli, language.unwrap()) {
-            Ok(_) => {
-                processed_count += 1;
+        // Settings come from the directory containing the entry, so that a
+        // nested `.cclean.toml` can exclude or reconfigure its own files.
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        let absolute = root.join(relative);
+        let scope = resolver.scope(absolute.parent().unwrap_or(&root))?;
+
+        if scope.is_excluded(&absolute)
+            || filter.excludes(relative)
+            || (!cli.no_ignore && scope.is_ignored(&absolute, is_dir))
+        {
+            if is_dir {
+                walker.skip_current_dir();
             }
-            Err(e) => {
-                logger::error_msg(&format!("{}: {}", path.display(), e));
+            logger::log_ignored(&path.display().to_string());
+            batch.ignored += 1;
+            continue;
+        }
+
+        if !path.is_file()
+            || entry.file_name() == constants::CACHE_FILE_NAME
+            || entry.file_name() == constants::CONFIG_FILE_NAME
+            || entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(constants::BACKUP_SUFFIX)
+            || !batch.seen.insert(absolute)
+        {
+            continue;
+        }
+
+        if !scope.accepts_extension(path, cli) || !filter.includes(relative) {
+            logger::log_filtered(&path.display().to_string());
+            batch.skip(path);
+            continue;
+        }
+
+        let language = if let Some(ref lang_str) = cli.language {
+            Language::from_name(lang_str).map(|language| (language, None))
+        } else {
+            resolve_language(&scope, path)
+        };
+
+        match language {
+            Some((language, rule)) => batch.jobs.push(Job {
+                path: path.to_path_buf(),
+                base: input.base.clone(),
+                language,
+                rule,
+                settings: scope.file_settings(cli, language),
+                size: entry.metadata().map_or(0, |m| m.len()),
+            }),
+            None => {
+                logger::log_skip(&path.display().to_string());
+                batch.skip(path);
             }
         }
     }
 
-    logger::log_summary(processed_count, skipped_count);
+    Ok(())
+}
 
+/// Adds a single file to the batch. Files named on the command line must
+/// have a known language and are not subject to ignore files; files matched
+/// by a pattern are filtered like files found in a directory.
+fn collect_file(
+    input: &Input,
+    cli: &Cli,
+    filter: &Filter,
+    resolver: &mut Resolver,
+    batch: &mut Batch,
+) -> Result<()> {
+    let file = &input.path;
+    let absolute = fs::canonicalize(file)?;
+    let scope = resolver.scope(absolute.parent().unwrap_or(&absolute))?;
+    let relative = match input.base {
+        Some(ref base) if !input.explicit => file.strip_prefix(base).unwrap_or(file),
+        _ => Path::new(file.file_name().unwrap_or(file.as_os_str())),
+    };
+
+    if !batch.seen.insert(absolute.clone()) {
+        return Ok(());
+    }
+    if filter.excludes(relative)
+        || (!input.explicit
+            && (scope.is_excluded(&absolute)
+                || (!cli.no_ignore && scope.is_ignored(&absolute, false))))
+    {
+        logger::log_ignored(&file.display().to_string());
+        batch.ignored += 1;
+        return Ok(());
+    }
+    if !filter.includes(relative) || (!input.explicit && !scope.accepts_extension(file, cli)) {
+        logger::log_filtered(&file.display().to_string());
+        batch.skip(file);
+        return Ok(());
+    }
+
+    let language = match cli.language {
+        Some(ref lang_str) => Some((
+            Language::from_name(lang_str)
+                .ok_or_else(|| exit::usage(constants::ERR_UNRECOGNIZED_LANG_CLI))?,
+            None,
+        )),
+        None => resolve_language(&scope, file),
+    };
+    let (language, rule) = match language {
+        Some(language) => language,
+        None if input.explicit => {
+            return Err(exit::usage(format!(
+                "{}: {}",
+                file.display(),
+                constants::ERR_UNRECOGNIZED_LANG_EXT
+            )))
+        }
+        None => {
+            logger::log_skip(&file.display().to_string());
+            batch.skip(file);
+            return Ok(());
+        }
+    };
+
+    batch.jobs.push(Job {
+        path: file.clone(),
+        base: input.base.clone(),
+        language,
+        rule,
+        settings: scope.file_settings(cli, language),
+        size: absolute.metadata().map_or(0, |m| m.len()),
+    });
     Ok(())
 }
 
-fn process_file(file: &Path, cli: &Cli) -> Result<()> {
-    let language = if let Some(ref lang_str) = cli.language {
-        Language::from_str(lang_str).context(constants::ERR_UNRECOGNIZED_LANG_CLI)?
-    } else {
-        Language::from_path(file).context(constants::ERR_UNRECOGNIZED_LANG_EXT)?
+/// Language for `path` from its extension or, if it has none, from its
+/// content, together with the rule that matched in the latter case.
+fn resolve_language(scope: &Scope, path: &Path) -> Option<(Language, Option<ContentRule>)> {
+    if let Some(language) = scope.language_for(path) {
+        return Some((language, None));
+    }
+    if path.extension().is_some() {
+        return None;
+    }
+    let (language, rule) = detect_language(path).ok()??;
+    Some((language, Some(rule)))
+}
+
+/// Runs [`Language::from_content`] on the start and the end of `path`.
+fn detect_language(path: &Path) -> io::Result<Option<(Language, ContentRule)>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut sample = Vec::new();
+    (&mut file)
+        .take(DETECT_SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+    if len > 2 * DETECT_SAMPLE_SIZE {
+        file.seek(SeekFrom::End(-(DETECT_SAMPLE_SIZE as i64)))?;
+        sample.push(b'\n');
+    }
+    file.read_to_end(&mut sample)?;
+    Ok(Language::from_content(&String::from_utf8_lossy(&sample)))
+}
+
+fn worker_count(cli: &Cli) -> usize {
+    match cli.jobs {
+        Some(jobs) if jobs > 0 => jobs,
+        _ => thread::available_parallelism().map_or(1, |n| n.get()),
+    }
+}
+
+/// Prints everything about one file in a single block so that output from
+/// parallel workers does not interleave.
+fn log_result(job: &Job, result: &Result<FileReport>) {
+    let _console = logger::lock();
+    let (file, language) = (job.path.as_path(), job.language);
+
+    let mut label = language.name().to_string();
+    if let Some(rule) = job.rule {
+        label = format!("{}, {}", label, rule);
+    }
+    if let Ok(FileReport {
+        encoding: Some(encoding),
+        ..
+    }) = result
+    {
+        label = format!("{}, {}", label, encoding);
+    }
+    logger::log_processing(&file.display().to_string(), &label);
+    match result {
+        Ok(report) if report.outcome == Outcome::Unchanged => logger::log_unchanged(),
+        Ok(report) if report.outcome == Outcome::Binary => logger::log_binary(),
+        Ok(report) if report.outcome == Outcome::WouldChange => {
+            logger::log_would_change(report.comments_removed);
+            logger::log_details(
+                report.comments_removed,
+                report.bytes_before,
+                report.bytes_after,
+            );
+            if let Some(ref diff) = report.diff {
+                logger::log_diff(&String::from_utf8_lossy(diff));
+            }
+        }
+        Ok(report) if report.outcome == Outcome::Clean => logger::log_clean(),
+        Ok(report) => {
+            if let Some(ref backup) = report.backup {
+                logger::log_backup(&backup.display().to_string());
+            }
+            match report.output {
+                Some(ref output) => logger::log_output(&output.display().to_string()),
+                None => logger::log_dry_run(),
+            }
+            logger::log_details(
+                report.comments_removed,
+                report.bytes_before,
+                report.bytes_after,
+            );
+            for diagnostic in &report.diagnostics {
+                logger::log_warning(&format!(
+                    "{}:{}:{}: {}",
+                    file.display(),
+                    diagnostic.line,
+                    diagnostic.column,
+                    diagnostic.kind.description()
+                ));
+            }
+        }
+        Err(e) => logger::error_msg(&format!("{}: {:#}", file.display(), e)),
+    }
+    if let Ok(report) = result {
+        for warning in &report.warnings {
+            logger::log_warning(warning);
+        }
+    }
+    logger::log_record(file_record(file, language, result));
+}
+
+fn file_record(file: &Path, language: Language, result: &Result<FileReport>) -> FileRecord {
+    let mut record = FileRecord {
+        path: file.to_path_buf(),
+        language: Some(language.name()),
+        action: Action::Processed,
+        reason: None,
+        comments_removed: 0,
+        bytes_before: None,
+        bytes_after: None,
+        output: None,
+        backup: None,
+        error: None,
     };
+    match result {
+        Ok(report) => {
+            record.reason = match report.outcome {
+                Outcome::Binary => Some(constants::REASON_BINARY),
+                Outcome::Unchanged => Some(constants::REASON_UNCHANGED),
+                _ => None,
+            };
+            if record.reason.is_some() {
+                record.action = Action::Skipped;
+            }
+            record.comments_removed = report.comments_removed;
+            record.bytes_before = Some(report.bytes_before);
+            record.bytes_after = report.bytes_after;
+            record.output = report.output.clone();
+            record.backup = report.backup.clone();
+        }
+        Err(e) => {
+            record.action = Action::Error;
+            record.error = Some(format!("{:#}", e));
+        }
+    }
+    record
+}
 
-    process_single_file(file, None, cli, language)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Cleaned,
+    /// Skipped by `--incremental`.
+    Unchanged,
+    Binary,
+    /// Checked by `--check` and already free of comments.
+    Clean,
+    /// Checked by `--check`; cleaning would change the file.
+    WouldChange,
 }
 
-fn process_single_file(
-    file: &Path,
-    base_dir: Option<&Path>,
-    cli: &Cli,
-    language: Language,
-) -> Result<()> {
-    logger::log_processing(&file.display().to_string(), language.name());
+struct FileReport {
+    outcome: Outcome,
+    comments_removed: usize,
+    bytes_before: u64,
+    /// Size of the cleaned output, unless skipped.
+    bytes_after: Option<u64>,
+    backup: Option<PathBuf>,
+    output: Option<PathBuf>,
+    diagnostics: Vec<Diagnostic>,
+    /// Only set for files that are not plain UTF-8.
+    encoding: Option<SourceEncoding>,
+    /// Set by `--diff` for files that would change.
+    diff: Option<Vec<u8>>,
+    /// This file's part of the `--patch` file.
+    patch: Option<Vec<u8>>,
+    /// Logged with the file's other lines rather than as they come up, so
+    /// that parallel workers do not interleave them.
+    warnings: Vec<String>,
+}
 
-    let content = fs::read_to_string(file).context(format!(
-        "{} {}",
-        constants::ERR_READ_FILE,
-        file.display()
-    ))?;
+/// Whether the run only reports what cleaning would change.
+fn is_preview(cli: &Cli) -> bool {
+    cli.check || cli.diff || cli.patch.is_some()
+}
 
-    let cleaned_content = cleaner::clean_comments(&content, language);
+fn process_single_file(job: &Job, run: &Run) -> Result<FileReport> {
+    let (cli, cache) = (run.cli, run.cache.as_ref());
+    let file = job.path.as_path();
+    let language = job.language;
+    let settings = &job.settings;
+    let options = &settings.options;
+    let bytes_before = fs::metadata(file)
+        .context(format!("{} {}", constants::ERR_READ_FILE, file.display()))?
+        .len();
+    let Some((input, encoding)) = open_source(file, settings.encoding)? else {
+        return Ok(FileReport {
+            outcome: Outcome::Binary,
+            comments_removed: 0,
+            bytes_before,
+            bytes_after: None,
+            backup: None,
+            output: None,
+            diagnostics: Vec::new(),
+            encoding: None,
+            diff: None,
+            patch: None,
+            warnings: Vec::new(),
+        });
+    };
+    let logged_encoding = (!encoding.is_plain_utf8()).then_some(encoding);
+
+    if is_preview(cli) {
+        let preview = preview_file(input, file, encoding, language, settings)?;
+        let changed = preview.original != preview.cleaned;
+        let diff = if cli.diff && changed {
+            let original = encoding::decode(&preview.original, encoding)?;
+            let cleaned = encoding::decode(&preview.cleaned, encoding)?;
+            Some(diff::unified(file, original.as_bytes(), cleaned.as_bytes()))
+        } else {
+            None
+        };
+        // UTF-16 files are binary to git, so no text patch applies to them,
+        // and git refuses paths outside the directory it is run from.
+        let mut warnings = Vec::new();
+        let patch = if cli.patch.is_some() && changed {
+            let excluded = if !encoding.encoding.is_ascii_compatible() {
+                Some(constants::MSG_NOT_IN_PATCH)
+            } else if diff::patch_path(file).is_none() {
+                Some(constants::MSG_OUTSIDE_PATCH)
+            } else {
+                None
+            };
+            match excluded {
+                Some(reason) => {
+                    warnings.push(format!("{}: {}", file.display(), reason));
+                    None
+                }
+                None => Some(diff::unified(file, &preview.original, &preview.cleaned)),
+            }
+        } else {
+            None
+        };
+        return Ok(FileReport {
+            outcome: if changed {
+                Outcome::WouldChange
+            } else {
+                Outcome::Clean
+            },
+            comments_removed: preview.report.comments_removed,
+            bytes_before,
+            bytes_after: Some(preview.cleaned.len() as u64),
+            backup: None,
+            output: None,
+            diagnostics: preview.report.diagnostics,
+            encoding: logged_encoding,
+            diff,
+            patch,
+            warnings,
+        });
+    }
 
     if cli.dry_run {
-        logger::log_dry_run();
-        return Ok(());
+        let read_error = || format!("{} {}", constants::ERR_READ_FILE, file.display());
+        // Encoded as it would be written, only to count the output's size.
+        let mut size = ByteCount::default();
+        let report = if settings.verify {
+            let mut cleaned = Vec::new();
+            let report =
+                clean_stream(input, &mut cleaned, language, options).with_context(read_error)?;
+            check_diagnostics(file, &report, settings.force)?;
+            verify_output(file, encoding, &String::from_utf8_lossy(&cleaned), language)?;
+            let mut writer = EncodeWriter::new(&mut size, encoding)?;
+            writer.write_all(&cleaned)?;
+            writer.finish()?;
+            report
+        } else {
+            let mut writer = EncodeWriter::new(&mut size, encoding)?;
+            let report =
+                clean_stream(input, &mut writer, language, options).with_context(read_error)?;
+            writer.finish()?;
+            check_diagnostics(file, &report, settings.force)?;
+            report
+        };
+        return Ok(FileReport {
+            outcome: Outcome::Cleaned,
+            comments_removed: report.comments_removed,
+            bytes_before,
+            bytes_after: Some(size.0),
+            backup: None,
+            output: None,
+            diagnostics: report.diagnostics,
+            encoding: logged_encoding,
+            diff: None,
+            patch: None,
+            warnings: Vec::new(),
+        });
     }
 
-    let output_path = determine_output_path(file, base_dir, cli)?;
+    let output_path = determine_output_path(file, job.base.as_deref(), cli)?;
+
+    // Hashed before anything is written, since in-place output replaces the input.
+    let cache_key = format!(
+        "{}|{:?}|{}|{}",
+        language.name(),
+        options,
+        settings.encoding.map_or("auto", |e| e.name()),
+        constants::APP_VERSION
+    );
+    let input_hash = match cache {
+        Some(cache) => {
+            let hash = cache::hash_file(file).context(format!(
+                "{} {}",
+                constants::ERR_READ_FILE,
+                file.display()
+            ))?;
+            if cache.is_unchanged(file, &hash, &output_path, &cache_key)? {
+                return Ok(FileReport {
+                    outcome: Outcome::Unchanged,
+                    comments_removed: 0,
+                    bytes_before,
+                    bytes_after: None,
+                    backup: None,
+                    output: Some(output_path),
+                    diagnostics: Vec::new(),
+                    encoding: logged_encoding,
+                    diff: None,
+                    patch: None,
+                    warnings: Vec::new(),
+                });
+            }
+            Some(hash)
+        }
+        None => None,
+    };
 
     if let Some(parent) = output_path.parent() {
         if !parent.exists() {
@@ -119,28 +980,241 @@ fn process_single_file(
         }
     }
 
-    if cli.backup && cli.in_place {
-        let backup_path = file.with_extension(format!(
-            "{}.bak",
-            file.extension().and_then(|s| s.to_str()).unwrap_or("")
-        ));
-        fs::copy(file, &backup_path).context(format!(
-            "{} {}",
-            constants::ERR_CREATE_BACKUP,
-            backup_path.display()
-        ))?;
+    let backup = match settings.backup {
+        Some(mode) if cli.in_place => Some(run.backups.create(file, mode)?),
+        _ => None,
+    };
+
+    let (report, bytes_after, written) = write_cleaned(
+        input,
+        file,
+        encoding,
+        &output_path,
+        language,
+        settings,
+        run.transaction.as_ref(),
+    )?;
 
-        logger::log_backup(&backup_path.display().to_string());
+    if let (Some(cache), Some(hash)) = (cache, input_hash) {
+        cache.record(file, hash, &output_path, &written, &cache_key)?;
     }
 
-    fs::write(&output_path, cleaned_content).context(format!(
-        "{} {}",
-        constants::ERR_WRITE_FILE,
-        output_path.display()
-    ))?;
+    Ok(FileReport {
+        outcome: Outcome::Cleaned,
+        comments_removed: report.comments_removed,
+        bytes_before,
+        bytes_after: Some(bytes_after),
+        backup,
+        output: Some(output_path),
+        diagnostics: report.diagnostics,
+        encoding: logged_encoding,
+        diff: None,
+        patch: None,
+        warnings: Vec::new(),
+    })
+}
 
-    logger::log_output(&output_path.display().to_string());
+/// Opens `file` for cleaning, decoding it to UTF-8 if it is in another
+/// encoding or starts with a BOM. Returns `None` for binary files.
+fn open_source(
+    file: &Path,
+    forced: Option<&'static encoding_rs::Encoding>,
+) -> Result<Option<(Box<dyn Read>, SourceEncoding)>> {
+    let read_error = || format!("{} {}", constants::ERR_READ_FILE, file.display());
+    let mut reader = BufReader::with_capacity(
+        encoding::SAMPLE_SIZE,
+        File::open(file).with_context(read_error)?,
+    );
+    let sample = reader.fill_buf().with_context(read_error)?;
+    let encoding = SourceEncoding::detect(sample, sample.len() < encoding::SAMPLE_SIZE, forced);
 
+    if encoding::is_binary(sample, encoding) {
+        Ok(None)
+    } else if encoding.is_plain_utf8() {
+        Ok(Some((Box::new(reader), encoding)))
+    } else {
+        Ok(Some((
+            Box::new(DecodeReader::new(reader, encoding)),
+            encoding,
+        )))
+    }
+}
+
+/// What cleaning a file would produce, for `--check`, `--diff` and `--patch`.
+struct Preview {
+    report: CleanReport,
+    original: Vec<u8>,
+    /// Encoded as it would be written.
+    cleaned: Vec<u8>,
+}
+
+/// Cleans `file` in memory without writing anything.
+fn preview_file(
+    input: Box<dyn Read>,
+    file: &Path,
+    encoding: SourceEncoding,
+    language: Language,
+    settings: &FileSettings,
+) -> Result<Preview> {
+    let read_error = || format!("{} {}", constants::ERR_READ_FILE, file.display());
+    let mut cleaned = Vec::new();
+    let mut writer = EncodeWriter::new(&mut cleaned, encoding)?;
+    let report =
+        clean_stream(input, &mut writer, language, &settings.options).with_context(read_error)?;
+    writer.finish()?;
+    check_diagnostics(file, &report, settings.force)?;
+    if settings.verify {
+        verify_output(
+            file,
+            encoding,
+            &encoding::decode(&cleaned, encoding)?,
+            language,
+        )?;
+    }
+    Ok(Preview {
+        report,
+        original: fs::read(file).with_context(read_error)?,
+        cleaned,
+    })
+}
+
+/// Streams the cleaned input into a temporary file next to `output_path` and
+/// moves it into place, so the source can be read while its own path is
+/// being rewritten and is never left half-written. The temporary file is
+/// discarded instead if the lexer reported unterminated constructs (without
+/// `--force`) or if `--verify` finds that the code changed. With a
+/// transaction, the file is left staged there instead of being moved.
+/// Returns the report, the size written and where the output is now.
+fn write_cleaned(
+    reader: impl Read,
+    source: &Path,
+    encoding: SourceEncoding,
+    output_path: &Path,
+    language: Language,
+    settings: &FileSettings,
+    transaction: Option<&Transaction>,
+) -> Result<(CleanReport, u64, PathBuf)> {
+    let error = || format!("{} {}", constants::ERR_WRITE_FILE, output_path.display());
+    // Taken before the rename, which replaces the source when cleaning in place.
+    let original = fs::metadata(source).ok();
+    let temp = AtomicFile::create(output_path).with_context(error)?;
+    let report = stage_cleaned(reader, encoding, &temp, language, settings).with_context(error)?;
+
+    // Refusals name the source and are not errors writing the output.
+    check_diagnostics(source, &report, settings.force)?;
+    if settings.verify {
+        let cleaned = fs::read(temp.temp_path())
+            .and_then(|bytes| encoding::decode(&bytes, encoding))
+            .with_context(error)?;
+        verify_output(source, encoding, &cleaned, language)?;
+    }
+
+    let size = temp.file().metadata().with_context(error)?.len();
+    let written = match transaction {
+        Some(transaction) => {
+            let staged = temp.temp_path().to_path_buf();
+            transaction
+                .stage(temp, original.as_ref(), settings.preserve_mtime)
+                .with_context(error)?;
+            staged
+        }
+        None => {
+            temp.commit(original.as_ref(), settings.preserve_mtime)
+                .with_context(error)?;
+            output_path.to_path_buf()
+        }
+    };
+    Ok((report, size, written))
+}
+
+/// Counts what is written to it and discards it.
+#[derive(Default)]
+struct ByteCount(u64);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn stage_cleaned(
+    reader: impl Read,
+    encoding: SourceEncoding,
+    temp: &AtomicFile,
+    language: Language,
+    settings: &FileSettings,
+) -> Result<CleanReport> {
+    let mut writer = EncodeWriter::new(BufWriter::new(temp.file()), encoding)?;
+    let report = clean_stream(reader, &mut writer, language, &settings.options)?;
+    writer.finish()?;
+    Ok(report)
+}
+
+/// Fails on the first unterminated construct unless `force` is set, since
+/// the lexer will have treated the rest of the file as part of it.
+fn check_diagnostics(source: &Path, report: &CleanReport, force: bool) -> Result<()> {
+    match report.diagnostics.first() {
+        Some(diagnostic) if !force => {
+            let more = match report.diagnostics.len() - 1 {
+                0 => String::new(),
+                n => format!(" (and {} more)", n),
+            };
+            anyhow::bail!(
+                "{} {}:{}:{}: {}{}. {}",
+                constants::ERR_UNTERMINATED,
+                source.display(),
+                diagnostic.line,
+                diagnostic.column,
+                diagnostic.kind.description(),
+                more,
+                constants::HINT_FORCE
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+fn verify_output(
+    source: &Path,
+    encoding: SourceEncoding,
+    cleaned: &str,
+    language: Language,
+) -> Result<()> {
+    let original = fs::read(source)
+        .and_then(|bytes| encoding::decode(&bytes, encoding))
+        .context(format!("{} {}", constants::ERR_READ_FILE, source.display()))?;
+    verify_text(source, &original, cleaned, language)
+}
+
+fn verify_text(source: &Path, original: &str, cleaned: &str, language: Language) -> Result<()> {
+    verify_clean(original, cleaned, language).context(format!(
+        "{} {}",
+        constants::ERR_VERIFY_FAILED,
+        source.display()
+    ))
+}
+
+/// Refuses a batch in which two files map to the same output, e.g. `a/x.c`
+/// and `b/x.c` with `-o out`, since one would silently overwrite the other.
+fn check_output_paths(batch: &Batch, cli: &Cli) -> Result<()> {
+    let mut outputs: HashMap<PathBuf, &Path> = HashMap::new();
+    for job in &batch.jobs {
+        let output = determine_output_path(&job.path, job.base.as_deref(), cli)?;
+        if let Some(other) = outputs.insert(output.clone(), &job.path) {
+            return Err(exit::usage(format!(
+                "{} {}, {} -> {}",
+                constants::ERR_OUTPUT_CONFLICT,
+                other.display(),
+                job.path.display(),
+                output.display()
+            )));
+        }
+    }
     Ok(())
 }
 
@@ -148,16 +1222,15 @@ fn determine_output_path(file: &Path, base_dir: Option<&Path>, cli: &Cli) -> Res
     if cli.in_place {
         Ok(file.to_path_buf())
     } else if let Some(ref output) = cli.output {
-        if cli.recursive {
-            if let Some(base) = base_dir {
-                let rel_path = file.strip_prefix(base).unwrap_or(file);
-                Ok(output.join(rel_path))
-            } else {
-                let file_name = file.file_name().context(constants::ERR_GET_FILENAME)?;
-                Ok(output.join(file_name))
-            }
-        } else {
-            Ok(output.clone())
+        match base_dir {
+            Some(base) => match file.strip_prefix(base) {
+                Ok(rel_path) => Ok(output.join(rel_path)),
+                Err(_) => {
+                    let file_name = file.file_name().context(constants::ERR_GET_FILENAME)?;
+                    Ok(output.join(file_name))
+                }
+            },
+            None => Ok(output.clone()),
         }
     } else {
         let parent = file.parent().unwrap_or_else(|| Path::new("."));
@@ -176,3 +1249,247 @@ fn determine_output_path(file: &Path, base_dir: Option<&Path>, cli: &Cli) -> Res
         Ok(parent.join(output_name))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cclean-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn run(args: &[&str]) -> Result<()> {
+        let cli = Cli::parse_from(["cclean", "--no-progress"].iter().chain(args));
+        process(&cli, &mut Resolver::default())
+    }
+
+    #[test]
+    fn test_conflicting_outputs_are_refused() {
+        let dir = temp_dir("conflict");
+        let (a, b, out) = (dir.join("a"), dir.join("b"), dir.join("out"));
+        for sub in [&a, &b] {
+            fs::create_dir_all(sub).unwrap();
+            fs::write(sub.join("x.c"), "int x; // x\n").unwrap();
+        }
+        let (a, b) = (a.join("x.c"), b.join("x.c"));
+
+        let err = run(&[
+            a.to_str().unwrap(),
+            b.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap_err();
+        assert_eq!(exit::Exit::of(&err), exit::Exit::Usage);
+        assert!(!out.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_transactional_runs_update_the_cache() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = temp_dir("transactional-cache");
+        let (src, out) = (dir.join("src"), dir.join("out"));
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.c"), "int a; // x\n").unwrap();
+        let (src, out) = (src.to_str().unwrap(), out.to_str().unwrap());
+
+        let args = ["-r", "--transactional", "--incremental"];
+        run(&[&[src, "-o", out][..], &args].concat()).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("out/a.c")).unwrap(),
+            "int a; \n"
+        );
+
+        // A second in-place run must find the file unchanged and leave it be.
+        run(&[&[src, "-i"][..], &args].concat()).unwrap();
+        let inode = fs::metadata(dir.join("src/a.c")).unwrap().ino();
+        run(&[&[src, "-i"][..], &args].concat()).unwrap();
+        assert_eq!(fs::metadata(dir.join("src/a.c")).unwrap().ino(), inode);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_discards_backups() {
+        let dir = temp_dir("rollback-backups");
+        fs::write(dir.join("a.c"), "int a; // x\n").unwrap();
+        fs::write(dir.join("b.c"), "int b; /* x\n").unwrap();
+
+        let dir_arg = dir.to_str().unwrap();
+        assert!(run(&[dir_arg, "-r", "-i", "-b", "--transactional"]).is_err());
+        assert_eq!(
+            fs::read_to_string(dir.join("a.c")).unwrap(),
+            "int a; // x\n"
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_run_reports_in_order() {
+        let dir = temp_dir("parallel");
+        let (src, out) = (dir.join("src"), dir.join("out"));
+        fs::create_dir_all(src.join("deep")).unwrap();
+        let mut expected = Vec::new();
+        for i in 0..12 {
+            let file = match i % 3 {
+                0 => src.join("deep").join(format!("f{:02}.c", i)),
+                _ => src.join(format!("f{:02}.c", i)),
+            };
+            // Larger files first, so that later jobs tend to finish earlier.
+            let body = "int x; // comment\n".repeat((12 - i) * 500);
+            fs::write(&file, body).unwrap();
+            expected.push(file);
+        }
+        fs::write(src.join("clean.c"), "int x;\n").unwrap();
+        fs::write(src.join("image.c"), b"\x89PNG\0\0\x01\x02").unwrap();
+        fs::write(src.join("notes.txt"), "no language\n").unwrap();
+        expected.extend([src.join("clean.c"), src.join("image.c")]);
+        expected.sort();
+
+        let cli = Cli::parse_from([
+            "cclean",
+            "--no-progress",
+            src.to_str().unwrap(),
+            "-r",
+            "-j",
+            "4",
+            "-o",
+            out.to_str().unwrap(),
+        ]);
+        let reported = Mutex::new(Vec::new());
+        let summary = process_with(&cli, &mut Resolver::default(), &|job, _| {
+            reported.lock().unwrap().push(job.path.clone());
+        })
+        .unwrap();
+
+        assert_eq!(reported.into_inner().unwrap(), expected);
+        assert_eq!(
+            (
+                summary.processed,
+                summary.binary,
+                summary.skipped,
+                summary.failed
+            ),
+            (13, 1, 1, 0)
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("deep").join("f00.c")).unwrap(),
+            "int x; \n".repeat(12 * 500)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_refusals_are_not_write_errors() {
+        let dir = temp_dir("refusals");
+        let file = dir.join("open.c");
+        fs::write(&file, "int a; /* never closed\n").unwrap();
+
+        let cli = Cli::parse_from([
+            "cclean",
+            "--no-progress",
+            file.to_str().unwrap(),
+            "-o",
+            dir.join("out.c").to_str().unwrap(),
+        ]);
+        let errors = Mutex::new(Vec::new());
+        process_with(&cli, &mut Resolver::default(), &|_, result| {
+            if let Err(e) = result {
+                errors.lock().unwrap().push(format!("{:#}", e));
+            }
+        })
+        .unwrap_err();
+
+        let errors = errors.into_inner().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with(constants::ERR_UNTERMINATED),
+            "{}",
+            errors[0]
+        );
+        assert!(
+            !errors[0].contains(constants::ERR_WRITE_FILE),
+            "{}",
+            errors[0]
+        );
+        assert!(!dir.join("out.c").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_writes_nothing() {
+        let dir = temp_dir("check");
+        fs::write(dir.join("a.c"), "int a; // x\n").unwrap();
+        fs::write(dir.join("b.c"), "int b;\n").unwrap();
+        let dir_arg = dir.to_str().unwrap();
+
+        let err = run(&[dir_arg, "-r", "--check"]).unwrap_err();
+        assert_eq!(exit::Exit::of(&err), exit::Exit::CheckFailed);
+        assert_eq!(
+            fs::read_to_string(dir.join("a.c")).unwrap(),
+            "int a; // x\n"
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // Explicit in-place and incremental flags do not make --check write.
+        let err = run(&[dir_arg, "-r", "-i", "--incremental", "--check"]).unwrap_err();
+        assert_eq!(exit::Exit::of(&err), exit::Exit::CheckFailed);
+        assert_eq!(
+            fs::read_to_string(dir.join("a.c")).unwrap(),
+            "int a; // x\n"
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        let b = dir.join("b.c");
+        run(&[b.to_str().unwrap(), "--check"]).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn filter(args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+        let cli = Cli::parse_from(["cclean", "-"].iter().chain(args));
+        let mut output = Vec::new();
+        process_stdin_with(&cli, &mut Resolver::default(), input, &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_stdin_filter() {
+        let python = b"x = 1  # one\n";
+        assert_eq!(
+            filter(&["--stdin-filename", "src/app.py"], python).unwrap(),
+            b"x = 1  \n"
+        );
+        assert_eq!(
+            filter(&[], b"#!/usr/bin/env python3\nx = 1  # one\n").unwrap(),
+            b"#!/usr/bin/env python3\nx = 1  \n"
+        );
+
+        let unknown = filter(&["--stdin-filename", "notes.unknown"], python).unwrap_err();
+        assert_eq!(exit::Exit::of(&unknown), exit::Exit::Usage);
+        let unnamed = filter(&[], python).unwrap_err();
+        assert_eq!(exit::Exit::of(&unnamed), exit::Exit::Usage);
+
+        let err = filter(&["--lang", "c", "--check"], b"int a; // x\n").unwrap_err();
+        assert_eq!(exit::Exit::of(&err), exit::Exit::CheckFailed);
+        assert!(filter(&["--lang", "c", "--check"], b"int a;\n")
+            .unwrap()
+            .is_empty());
+
+        let binary = b"int a; // \0\x01\x02\n";
+        assert_eq!(filter(&["--lang", "c"], binary).unwrap(), binary);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::language::Language;
+use crate::lexer::{BufferedLexer, CommentSpan, HtmlLexer, StreamLexer};
+use crate::spec::{LanguageSpec, SpecSyntax};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+/// Describes how comments are written in one language.
+///
+/// Implement this to teach the cleaner a new language and hand it to
+/// [`register_language`].
+pub trait CommentSyntax: Send + Sync {
+    /// Display name, also accepted as a language identifier.
+    fn name(&self) -> &str;
+
+    /// File extensions without the leading dot.
+    fn extensions(&self) -> Vec<&str>;
+
+    /// Extra identifiers accepted by `--lang` and [`Language::from_name`].
+    fn aliases(&self) -> Vec<&str>;
+
+    /// Interpreter names that select this language from a `#!` line, e.g.
+    /// `python3`.
+    fn interpreters(&self) -> Vec<&str> {
+        Vec::new()
+    }
+
+    /// Appends every comment in `content` to `spans`, in order. Only `range`,
+    /// `kind` and `language` need to be filled in; line and column are
+    /// computed by the caller.
+    fn lex(&self, content: &str, language: Language, spans: &mut Vec<CommentSpan>);
+
+    /// Returns a lexer that can be fed input in chunks. The default buffers
+    /// the whole input and calls [`CommentSyntax::lex`] at the end.
+    fn stream_lexer(&self, language: Language) -> Box<dyn StreamLexer + '_> {
+        Box::new(BufferedLexer::new(self, language))
+    }
+}
+
+struct HtmlSyntax;
+
+impl CommentSyntax for HtmlSyntax {
+    fn name(&self) -> &str {
+        "HTML"
+    }
+
+    fn extensions(&self) -> Vec<&str> {
+        vec!["html", "htm"]
+    }
+
+    fn aliases(&self) -> Vec<&str> {
+        vec!["html"]
+    }
+
+    fn lex(&self, content: &str, language: Language, spans: &mut Vec<CommentSpan>) {
+        let mut lexer = HtmlLexer::new(language);
+        lexer.feed(content, 0, true, spans);
+        lexer.finish(content.len(), spans);
+    }
+
+    fn stream_lexer(&self, language: Language) -> Box<dyn StreamLexer + '_> {
+        Box::new(HtmlLexer::new(language))
+    }
+}
+
+// Order must match the associated constants on `Language`; `None` marks HTML.
+const BUILTIN_SPECS: [Option<&str>; 11] = [
+    Some(include_str!("../languages/c.toml")),
+    Some(include_str!("../languages/cpp.toml")),
+    Some(include_str!("../languages/java.toml")),
+    Some(include_str!("../languages/javascript.toml")),
+    Some(include_str!("../languages/typescript.toml")),
+    Some(include_str!("../languages/python.toml")),
+    None,
+    Some(include_str!("../languages/css.toml")),
+    Some(include_str!("../languages/php.toml")),
+    Some(include_str!("../languages/rust.toml")),
+    Some(include_str!("../languages/basic.toml")),
+];
+
+#[derive(Default)]
+struct Registry {
+    languages: Vec<&'static dyn CommentSyntax>,
+    by_extension: HashMap<String, Language>,
+    by_alias: HashMap<String, Language>,
+    by_interpreter: HashMap<String, Language>,
+}
+
+impl Registry {
+    fn add(&mut self, syntax: &'static dyn CommentSyntax) -> Language {
+        let language = Language(self.languages.len());
+        self.languages.push(syntax);
+
+        for ext in syntax.extensions() {
+            self.by_extension.insert(ext.to_lowercase(), language);
+        }
+        self.by_alias.insert(syntax.name().to_lowercase(), language);
+        for alias in syntax.aliases() {
+            self.by_alias.insert(alias.to_lowercase(), language);
+        }
+        for interpreter in syntax.interpreters() {
+            self.by_interpreter
+                .insert(interpreter.to_string(), language);
+        }
+
+        language
+    }
+}
+
+fn registry() -> &'static RwLock<Registry> {
+    static REGISTRY: OnceLock<RwLock<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut registry = Registry::default();
+        for source in BUILTIN_SPECS {
+            let syntax: &'static dyn CommentSyntax = match source {
+                Some(source) => {
+                    let spec = LanguageSpec::from_toml(source).expect("invalid built-in language");
+                    Box::leak(Box::new(SpecSyntax::new(spec)))
+                }
+                None => &HtmlSyntax,
+            };
+            registry.add(syntax);
+        }
+        RwLock::new(registry)
+    })
+}
+
+/// Adds a language to the global registry and returns its handle.
+///
+/// Extensions, aliases and interpreters that are already taken are re-pointed at the new
+/// language, so registering a syntax for `h` makes headers use it from then on.
+pub fn register_language<S: CommentSyntax + 'static>(syntax: S) -> Language {
+    let syntax: &'static dyn CommentSyntax = Box::leak(Box::new(syntax));
+    registry().write().unwrap().add(syntax)
+}
+
+pub(crate) fn syntax(language: Language) -> &'static dyn CommentSyntax {
+    registry().read().unwrap().languages[language.0]
+}
+
+pub(crate) fn len() -> usize {
+    registry().read().unwrap().languages.len()
+}
+
+pub(crate) fn find_by_extension(ext: &str) -> Option<Language> {
+    registry()
+        .read()
+        .unwrap()
+        .by_extension
+        .get(&ext.to_lowercase())
+        .copied()
+}
+
+pub(crate) fn find_by_alias(name: &str) -> Option<Language> {
+    registry()
+        .read()
+        .unwrap()
+        .by_alias
+        .get(&name.to_lowercase())
+        .copied()
+}
+
+pub(crate) fn find_by_interpreter(name: &str) -> Option<Language> {
+    registry().read().unwrap().by_interpreter.get(name).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{lex_comments, push_span, CommentKind};
+    use std::path::Path;
+
+    struct SemicolonSyntax;
+
+    impl CommentSyntax for SemicolonSyntax {
+        fn name(&self) -> &str {
+            "Semicolon Test"
+        }
+
+        fn extensions(&self) -> Vec<&str> {
+            vec!["semitest"]
+        }
+
+        fn aliases(&self) -> Vec<&str> {
+            vec!["semi-test"]
+        }
+
+        fn lex(&self, content: &str, language: Language, spans: &mut Vec<CommentSpan>) {
+            let mut offset = 0;
+            for line in content.split_inclusive('\n') {
+                if let Some(pos) = line.find(';') {
+                    let end = offset + line.trim_end_matches(['\r', '\n']).len();
+                    push_span(spans, offset + pos..end, CommentKind::Line, language);
+                }
+                offset += line.len();
+            }
+        }
+    }
+
+    #[test]
+    fn test_builtin_lookup() {
+        assert_eq!(Language::from_extension("HPP"), Some(Language::Cpp));
+        assert_eq!(Language::from_name("c++"), Some(Language::Cpp));
+        assert_eq!(
+            Language::from_name("JavaScript"),
+            Some(Language::JavaScript)
+        );
+        assert_eq!(Language::Basic.name(), "Basic");
+        assert_eq!(Language::from_extension("unknown"), None);
+    }
+
+    #[test]
+    fn test_register_custom_language() {
+        let language = register_language(SemicolonSyntax);
+        assert_eq!(Language::from_path(Path::new("a.semitest")), Some(language));
+        assert_eq!(Language::from_name("semi-test"), Some(language));
+        assert_eq!(language.name(), "Semicolon Test");
+
+        let spans = lex_comments("mov a, 1 ; load\nret\n", language);
+        assert_eq!(spans.len(), 1);
+        assert_eq!((spans[0].line, spans[0].column), (1, 10));
+        assert!(Language::all().contains(&language));
+    }
+}
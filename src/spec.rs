@@ -0,0 +1,676 @@
+use crate::language::Language;
+use crate::lexer::{
+    push_span, CommentKind, CommentSpan, Cursor, Diagnostic, DiagnosticKind, StreamLexer,
+};
+use crate::registry::{self, CommentSyntax};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// Declarative description of a language whose comments only differ from
+/// others in their delimiters. See `languages/*.toml` for the built-in specs.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageSpec {
+    pub name: String,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Interpreters named on a `#!` line, e.g. `python3`.
+    #[serde(default)]
+    pub interpreters: Vec<String>,
+    #[serde(default)]
+    pub line_comments: Vec<String>,
+    #[serde(default)]
+    pub block_comments: Vec<BlockDelimiters>,
+    /// Whether block comments nest, as in Rust.
+    #[serde(default)]
+    pub nested_comments: bool,
+    /// Comment openers that mark documentation, e.g. `///` or `/**`.
+    #[serde(default)]
+    pub doc_prefixes: Vec<String>,
+    /// Case-insensitive words that start a line comment, e.g. Basic's `REM`.
+    #[serde(default)]
+    pub keyword_comments: Vec<String>,
+    #[serde(default)]
+    pub strings: Vec<StringDelimiters>,
+    /// Recognize JavaScript-style `/regex/` literals.
+    #[serde(default)]
+    pub regex_literals: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDelimiters {
+    pub open: String,
+    pub close: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StringDelimiters {
+    pub open: String,
+    /// Defaults to `open`.
+    #[serde(default)]
+    pub close: Option<String>,
+    #[serde(default)]
+    pub escape: Option<String>,
+    /// Whether the literal may span lines; single-line literals end at the newline.
+    #[serde(default)]
+    pub multiline: bool,
+    /// Only a single character or escape sequence may sit between the
+    /// delimiters, so that e.g. a Rust lifetime `'a` is not taken for a literal.
+    #[serde(default)]
+    pub char_literal: bool,
+}
+
+impl StringDelimiters {
+    fn close(&self) -> &str {
+        self.close.as_deref().unwrap_or(&self.open)
+    }
+
+    fn opens(&self, rest: &[u8]) -> bool {
+        if !rest.starts_with(self.open.as_bytes()) {
+            return false;
+        }
+        if !self.char_literal {
+            return true;
+        }
+        let body = &rest[self.open.len()..];
+        if let Some(ref escape) = self.escape {
+            if body.starts_with(escape.as_bytes()) {
+                return true;
+            }
+        }
+        body.first().is_some_and(|&b| {
+            body[char_len(b).min(body.len())..].starts_with(self.close().as_bytes())
+        })
+    }
+}
+
+impl LanguageSpec {
+    pub fn from_toml(source: &str) -> Result<Self> {
+        let spec: LanguageSpec = toml::from_str(source)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read language definition: {}", path.display()))?;
+        Self::from_toml(&source)
+            .with_context(|| format!("Invalid language definition: {}", path.display()))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            anyhow::bail!("`name` must not be empty");
+        }
+        if self.line_comments.is_empty()
+            && self.block_comments.is_empty()
+            && self.keyword_comments.is_empty()
+        {
+            anyhow::bail!("at least one comment delimiter is required");
+        }
+
+        let delimiters = self
+            .line_comments
+            .iter()
+            .chain(&self.doc_prefixes)
+            .chain(&self.keyword_comments)
+            .chain(self.block_comments.iter().flat_map(|b| [&b.open, &b.close]))
+            .chain(self.strings.iter().map(|s| &s.open))
+            .chain(self.strings.iter().filter_map(|s| s.close.as_ref()))
+            .chain(self.strings.iter().filter_map(|s| s.escape.as_ref()));
+        for delimiter in delimiters {
+            if delimiter.is_empty() {
+                anyhow::bail!("delimiters must not be empty");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lookahead(&self) -> usize {
+        let char_literals = self
+            .strings
+            .iter()
+            .filter(|s| s.char_literal)
+            .map(|s| s.open.len() + 4 + s.close().len());
+        self.line_comments
+            .iter()
+            .chain(&self.doc_prefixes)
+            .chain(&self.keyword_comments)
+            .chain(self.block_comments.iter().flat_map(|b| [&b.open, &b.close]))
+            .chain(self.strings.iter().map(|s| &s.open))
+            .map(String::len)
+            .chain(char_literals)
+            .max()
+            .unwrap_or(1)
+            + 1
+    }
+}
+
+/// A [`CommentSyntax`] backed by a [`LanguageSpec`].
+#[derive(Debug, Clone)]
+pub struct SpecSyntax {
+    spec: LanguageSpec,
+}
+
+impl SpecSyntax {
+    pub fn new(spec: LanguageSpec) -> Self {
+        Self { spec }
+    }
+
+    pub fn spec(&self) -> &LanguageSpec {
+        &self.spec
+    }
+}
+
+impl CommentSyntax for SpecSyntax {
+    fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    fn extensions(&self) -> Vec<&str> {
+        self.spec.extensions.iter().map(String::as_str).collect()
+    }
+
+    fn aliases(&self) -> Vec<&str> {
+        self.spec.aliases.iter().map(String::as_str).collect()
+    }
+
+    fn interpreters(&self) -> Vec<&str> {
+        self.spec.interpreters.iter().map(String::as_str).collect()
+    }
+
+    fn lex(&self, content: &str, language: Language, spans: &mut Vec<CommentSpan>) {
+        let mut lexer = SpecLexer::new(&self.spec, language);
+        lexer.feed(content, 0, true, spans);
+        lexer.finish(content.len(), spans);
+    }
+
+    fn stream_lexer(&self, language: Language) -> Box<dyn StreamLexer + '_> {
+        Box::new(SpecLexer::new(&self.spec, language))
+    }
+}
+
+/// Loads a language definition file and registers it.
+pub fn register_spec_file(path: &Path) -> Result<Language> {
+    let spec = LanguageSpec::from_file(path)?;
+    Ok(registry::register_language(SpecSyntax::new(spec)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Code,
+    Line {
+        start: usize,
+        doc: Option<usize>,
+    },
+    Block {
+        start: usize,
+        index: usize,
+        depth: usize,
+        doc: Option<usize>,
+    },
+    Str {
+        index: usize,
+        start: usize,
+    },
+    Regex {
+        in_class: bool,
+    },
+}
+
+enum Opener {
+    Line,
+    Block(usize),
+    Str(usize),
+}
+
+/// Resumable lexer driven by a [`LanguageSpec`].
+pub(crate) struct SpecLexer<'a> {
+    spec: &'a LanguageSpec,
+    language: Language,
+    lookahead: usize,
+    state: State,
+    prev_non_ws: Option<u8>,
+    prev_byte: Option<u8>,
+    cursor: Cursor,
+    opened: Cursor,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> SpecLexer<'a> {
+    pub(crate) fn new(spec: &'a LanguageSpec, language: Language) -> Self {
+        Self {
+            spec,
+            language,
+            lookahead: spec.lookahead(),
+            state: State::Code,
+            prev_non_ws: None,
+            prev_byte: None,
+            cursor: Cursor::new(),
+            opened: Cursor::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+}
+
+impl StreamLexer for SpecLexer<'_> {
+    fn feed(&mut self, buf: &str, base: usize, eof: bool, spans: &mut Vec<CommentSpan>) -> usize {
+        let bytes = buf.as_bytes();
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let enough = |n: usize| eof || bytes.len() - i >= n;
+            let step = match self.state {
+                State::Code => {
+                    if !enough(self.lookahead) {
+                        break;
+                    }
+                    self.step_code(bytes, i, base)
+                }
+                State::Line { start, doc } => match bytes[i..].iter().position(|&b| b == b'\n') {
+                    Some(p) => {
+                        let newline = i + p;
+                        let before = if newline > 0 {
+                            Some(bytes[newline - 1])
+                        } else {
+                            self.prev_byte
+                        };
+                        let mut end = base + newline;
+                        if before == Some(b'\r') && end > start {
+                            end -= 1;
+                        }
+                        self.close_comment(start, end, doc, 0, spans);
+                        self.state = State::Code;
+                        p
+                    }
+                    // Hold back a trailing `\r` so a CRLF split across chunks
+                    // still ends the comment before the `\r`.
+                    None if !eof && bytes[bytes.len() - 1] == b'\r' => {
+                        if bytes.len() - 1 == i {
+                            break;
+                        }
+                        bytes.len() - 1 - i
+                    }
+                    None => bytes.len() - i,
+                },
+                State::Block {
+                    start,
+                    index,
+                    depth,
+                    doc,
+                } => {
+                    let block = &self.spec.block_comments[index];
+                    if !enough(block.open.len().max(block.close.len())) {
+                        break;
+                    }
+                    let rest = &bytes[i..];
+                    if rest.starts_with(block.close.as_bytes()) {
+                        let len = block.close.len();
+                        if depth == 1 {
+                            self.close_comment(start, base + i + len, doc, len, spans);
+                            self.state = State::Code;
+                        } else {
+                            self.state = State::Block {
+                                start,
+                                index,
+                                depth: depth - 1,
+                                doc,
+                            };
+                        }
+                        len
+                    } else if self.spec.nested_comments && rest.starts_with(block.open.as_bytes()) {
+                        self.state = State::Block {
+                            start,
+                            index,
+                            depth: depth + 1,
+                            doc,
+                        };
+                        block.open.len()
+                    } else {
+                        char_len(bytes[i])
+                    }
+                }
+                State::Str { index, start } => {
+                    let string = &self.spec.strings[index];
+                    let close = string.close();
+                    let escape = string.escape.as_deref().unwrap_or("");
+                    if !enough(close.len().max(escape.len() + 4)) {
+                        break;
+                    }
+                    let rest = &bytes[i..];
+                    if !escape.is_empty() && rest.starts_with(escape.as_bytes()) {
+                        let escaped = rest.get(escape.len()).map_or(0, |&b| char_len(b));
+                        (escape.len() + escaped).min(rest.len())
+                    } else if rest.starts_with(close.as_bytes()) {
+                        self.state = State::Code;
+                        self.prev_non_ws = close.as_bytes().last().copied();
+                        close.len()
+                    } else {
+                        if bytes[i] == b'\n' && !string.multiline {
+                            self.unterminated(DiagnosticKind::UnterminatedString, start);
+                            self.state = State::Code;
+                        }
+                        char_len(bytes[i])
+                    }
+                }
+                State::Regex { in_class } => {
+                    if !enough(4) {
+                        break;
+                    }
+                    match bytes[i] {
+                        b'\\' => {
+                            (1 + bytes.get(i + 1).map_or(0, |&b| char_len(b))).min(bytes.len() - i)
+                        }
+                        b'[' => {
+                            self.state = State::Regex { in_class: true };
+                            1
+                        }
+                        b']' => {
+                            self.state = State::Regex { in_class: false };
+                            1
+                        }
+                        b'/' if !in_class => {
+                            self.state = State::Code;
+                            self.prev_non_ws = Some(b'/');
+                            1
+                        }
+                        b'\n' => {
+                            self.state = State::Code;
+                            1
+                        }
+                        b => char_len(b),
+                    }
+                }
+            };
+
+            let step = step.min(bytes.len() - i);
+            if step > 0 {
+                self.prev_byte = Some(bytes[i + step - 1]);
+            }
+            self.cursor.advance(&bytes[i..i + step]);
+            i += step;
+        }
+
+        i
+    }
+
+    fn finish(&mut self, end: usize, spans: &mut Vec<CommentSpan>) {
+        match self.state {
+            State::Line { start, doc } => self.close_comment(start, end, doc, 0, spans),
+            State::Block { start, doc, .. } => {
+                self.close_comment(start, end, doc, 0, spans);
+                self.unterminated(DiagnosticKind::UnterminatedComment, start);
+            }
+            State::Str { start, .. } => {
+                self.unterminated(DiagnosticKind::UnterminatedString, start);
+            }
+            _ => {}
+        }
+        self.state = State::Code;
+    }
+
+    fn pending_start(&self) -> Option<usize> {
+        match self.state {
+            State::Line { start, .. } | State::Block { start, .. } => Some(start),
+            _ => None,
+        }
+    }
+
+    fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+impl SpecLexer<'_> {
+    fn step_code(&mut self, bytes: &[u8], i: usize, base: usize) -> usize {
+        let rest = &bytes[i..];
+
+        if let Some(len) = self.match_keyword(bytes, i) {
+            self.state = State::Line {
+                start: base + i,
+                doc: None,
+            };
+            return len;
+        }
+
+        if let Some((opener, len)) = self.match_opener(rest) {
+            match opener {
+                Opener::Line => {
+                    self.state = State::Line {
+                        start: base + i,
+                        doc: self.match_doc_prefix(rest),
+                    };
+                }
+                Opener::Block(index) => {
+                    self.opened = self.cursor;
+                    self.state = State::Block {
+                        start: base + i,
+                        index,
+                        depth: 1,
+                        doc: self.match_doc_prefix(rest),
+                    };
+                }
+                Opener::Str(index) => {
+                    self.opened = self.cursor;
+                    self.state = State::Str {
+                        index,
+                        start: base + i,
+                    };
+                }
+            }
+            return len;
+        }
+
+        let ch = bytes[i];
+        if self.spec.regex_literals && ch == b'/' && self.likely_regex_start(rest) {
+            self.state = State::Regex { in_class: false };
+            return 1;
+        }
+
+        if !ch.is_ascii_whitespace() {
+            self.prev_non_ws = Some(ch);
+        }
+        char_len(ch)
+    }
+
+    fn match_opener(&self, rest: &[u8]) -> Option<(Opener, usize)> {
+        let lines = self
+            .spec
+            .line_comments
+            .iter()
+            .map(|d| (Opener::Line, d.as_str()));
+        let blocks = self
+            .spec
+            .block_comments
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (Opener::Block(i), d.open.as_str()));
+        let strings = self
+            .spec
+            .strings
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.opens(rest))
+            .map(|(i, d)| (Opener::Str(i), d.open.as_str()));
+
+        let mut best: Option<(Opener, usize)> = None;
+        for (opener, delimiter) in lines.chain(blocks).chain(strings) {
+            if rest.starts_with(delimiter.as_bytes())
+                && best.as_ref().is_none_or(|(_, len)| delimiter.len() > *len)
+            {
+                best = Some((opener, delimiter.len()));
+            }
+        }
+        best
+    }
+
+    fn match_doc_prefix(&self, rest: &[u8]) -> Option<usize> {
+        self.spec
+            .doc_prefixes
+            .iter()
+            .map(String::as_bytes)
+            .filter(|prefix| rest.starts_with(prefix) && rest.get(prefix.len()) != prefix.last())
+            .map(<[u8]>::len)
+            .max()
+    }
+
+    fn match_keyword(&self, bytes: &[u8], i: usize) -> Option<usize> {
+        if self.prev_byte.is_some_and(is_ident_byte) {
+            return None;
+        }
+        self.spec.keyword_comments.iter().find_map(|keyword| {
+            let end = i + keyword.len();
+            let matched = bytes.len() >= end
+                && bytes[i..end].eq_ignore_ascii_case(keyword.as_bytes())
+                && bytes.get(end).is_none_or(|b| b.is_ascii_whitespace());
+            matched.then_some(keyword.len())
+        })
+    }
+
+    fn likely_regex_start(&self, rest: &[u8]) -> bool {
+        if matches!(rest.get(1), None | Some(b'=')) {
+            return false;
+        }
+        match self.prev_non_ws {
+            None => true,
+            Some(p) => {
+                !(is_ident_byte(p)
+                    || matches!(p, b')' | b']' | b'}' | b'.' | b'$' | b'"' | b'\'' | b'`'))
+            }
+        }
+    }
+
+    fn unterminated(&mut self, kind: DiagnosticKind, start: usize) {
+        let diagnostic = self.opened.diagnostic(kind, start, self.language);
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn close_comment(
+        &self,
+        start: usize,
+        end: usize,
+        doc: Option<usize>,
+        close_len: usize,
+        spans: &mut Vec<CommentSpan>,
+    ) {
+        let kind = match (doc, self.state) {
+            (Some(prefix), _) if end - start > prefix + close_len => CommentKind::Doc,
+            (_, State::Block { .. }) => CommentKind::Block,
+            _ => CommentKind::Line,
+        };
+        push_span(spans, start..end, kind, self.language);
+    }
+}
+
+fn char_len(lead: u8) -> usize {
+    match lead {
+        0xF0..=0xFF => 4,
+        0xE0..=0xEF => 3,
+        0xC0..=0xDF => 2,
+        _ => 1,
+    }
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_comments;
+
+    const LISP_SPEC: &str = r##"
+        name = "Spec Lisp"
+        extensions = ["speclisp"]
+        line_comments = [";"]
+        block_comments = [{ open = "#|", close = "|#" }]
+        nested_comments = true
+        doc_prefixes = [";;;"]
+        strings = [{ open = "\"", escape = "\\", multiline = true }]
+    "##;
+
+    #[test]
+    fn test_spec_from_toml() {
+        let spec = LanguageSpec::from_toml(LISP_SPEC).unwrap();
+        assert_eq!(spec.name, "Spec Lisp");
+        assert!(spec.nested_comments);
+        assert_eq!(spec.strings[0].close(), "\"");
+    }
+
+    #[test]
+    fn test_spec_rejects_invalid_definitions() {
+        assert!(LanguageSpec::from_toml("name = \"x\"").is_err());
+        assert!(LanguageSpec::from_toml("name = \"x\"\nline_comments = [\"\"]").is_err());
+        assert!(LanguageSpec::from_toml("name = \"x\"\nline_comment = [\";\"]").is_err());
+    }
+
+    #[test]
+    fn test_spec_language_lexes() {
+        let language = registry::register_language(SpecSyntax::new(
+            LanguageSpec::from_toml(LISP_SPEC).unwrap(),
+        ));
+        let input = ";;; doc\n(print \"; kept\") #| outer #| inner |# still |# (x) ; tail";
+        let texts: Vec<(&str, CommentKind)> = lex_comments(input, language)
+            .iter()
+            .map(|s| (s.text(input), s.kind))
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                (";;; doc", CommentKind::Doc),
+                ("#| outer #| inner |# still |#", CommentKind::Block),
+                ("; tail", CommentKind::Line),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rust_lifetimes_are_not_char_literals() {
+        let input = "fn f(s: &'a str) { g(\"it's // kept\", '\\'', 'x') } // gone\n/* c */";
+        let texts: Vec<&str> = lex_comments(input, Language::Rust)
+            .iter()
+            .map(|s| s.text(input))
+            .collect();
+        assert_eq!(texts, vec!["// gone", "/* c */"]);
+    }
+
+    #[test]
+    fn test_rust_raw_strings() {
+        let input = "let s = r#\"say \"// hi\"\"#; let t = r\"\\\"; // gone";
+        let texts: Vec<&str> = lex_comments(input, Language::Rust)
+            .iter()
+            .map(|s| s.text(input))
+            .collect();
+        assert_eq!(texts, vec!["// gone"]);
+    }
+
+    #[test]
+    fn test_feed_resumes_across_chunks() {
+        let spec = LanguageSpec::from_toml(include_str!("../languages/javascript.toml")).unwrap();
+        let input = "let a = \"/* no */\"; /* one\ntwo */ let re = /\\/\\//; // end\r\nx";
+        for split in 1..input.len() {
+            let mut lexer = SpecLexer::new(&spec, Language::JavaScript);
+            let mut spans = Vec::new();
+            let consumed = lexer.feed(&input[..split], 0, false, &mut spans);
+            let rest = &input[consumed..];
+            let consumed_rest = lexer.feed(rest, consumed, true, &mut spans);
+            assert_eq!(consumed + consumed_rest, input.len());
+            lexer.finish(input.len(), &mut spans);
+            let ranges: Vec<&str> = spans.iter().map(|s| &input[s.range.clone()]).collect();
+            assert_eq!(
+                ranges,
+                vec!["/* one\ntwo */", "// end"],
+                "split at {}",
+                split
+            );
+        }
+    }
+}
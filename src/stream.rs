@@ -0,0 +1,231 @@
+use crate::cleaner::{CleanOptions, EmptyLineWriter};
+use crate::language::Language;
+use crate::lexer::{self, Diagnostic};
+use std::io::{self, Read, Write};
+use std::str;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What [`clean_stream`] did to its input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CleanReport {
+    pub comments_removed: usize,
+    /// Strings and comments that were never closed. An unterminated block
+    /// comment runs to the end of input, so everything after its opening
+    /// delimiter has been dropped from the output.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Removes comments from `reader` and writes the result to `writer`.
+///
+/// Input is lexed in fixed-size chunks with the lexer state carried across
+/// chunk boundaries, so memory use does not grow with the input size. The
+/// exception is a custom [`CommentSyntax`](crate::CommentSyntax) without its
+/// own `stream_lexer`, which is buffered in full.
+pub fn clean_stream<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    language: Language,
+    options: &CleanOptions,
+) -> io::Result<CleanReport> {
+    clean_stream_chunked(reader, writer, language, options, CHUNK_SIZE)
+}
+
+fn clean_stream_chunked<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    language: Language,
+    options: &CleanOptions,
+    chunk_size: usize,
+) -> io::Result<CleanReport> {
+    let mut lexer = language.syntax().stream_lexer(language);
+    let mut output = EmptyLineWriter::new(writer, options.collapse_empty_lines);
+    let mut chunk = vec![0u8; chunk_size];
+    let mut undecoded: Vec<u8> = Vec::new();
+    let mut pending = String::new();
+    let mut base = 0;
+    let mut emitted = 0;
+    let mut spans = Vec::new();
+    let mut removed = 0;
+    // The start of the first line, kept until it is known whether it is a
+    // `#!` line and, if it is, for as long as the input is read.
+    let mut first_line = String::new();
+    let mut shebang = None;
+
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let eof = read == 0;
+
+        undecoded.extend_from_slice(&chunk[..read]);
+        let valid = match str::from_utf8(&undecoded) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() && !eof => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        let text = str::from_utf8(&undecoded[..valid]).unwrap();
+        if shebang.is_none() {
+            first_line.push_str(text);
+            let undecided = first_line.len() < 3 && "#![".starts_with(first_line.as_str());
+            if eof || first_line.contains('\n') || (!undecided && !first_line.starts_with("#!")) {
+                let len = lexer::shebang_len(&first_line);
+                first_line.truncate(len);
+                shebang = Some(len);
+            }
+        }
+        pending.push_str(text);
+        undecoded.drain(..valid);
+
+        let mut consumed = lexer.feed(&pending, base, eof, &mut spans);
+        let mut limit = base + consumed;
+        if eof {
+            consumed = pending.len();
+            limit = base + consumed;
+            lexer.finish(limit, &mut spans);
+        } else if let Some(start) = lexer.pending_start() {
+            limit = limit.min(start);
+        }
+
+        for span in spans.drain(..) {
+            if span.range.end <= shebang.unwrap_or(0) {
+                // Written out like code, from the copy kept in `first_line`
+                // since the lexer may already have consumed it.
+                if span.range.end > emitted {
+                    output.write_str(&first_line[emitted..span.range.end])?;
+                    emitted = span.range.end;
+                }
+                continue;
+            }
+            if span.range.start > emitted {
+                output.write_str(&pending[emitted - base..span.range.start - base])?;
+            }
+            emitted = emitted.max(span.range.end);
+            removed += 1;
+        }
+        if limit > emitted {
+            output.write_str(&pending[emitted - base..limit - base])?;
+            emitted = limit;
+        }
+
+        pending.drain(..consumed);
+        base += consumed;
+
+        if eof {
+            break;
+        }
+    }
+
+    output.finish()?;
+    Ok(CleanReport {
+        comments_removed: removed,
+        diagnostics: lexer.diagnostics().to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cleaner::clean_comments;
+    use crate::lexer::DiagnosticKind;
+
+    fn stream(input: &str, language: Language, chunk_size: usize) -> String {
+        let mut output = Vec::new();
+        clean_stream_chunked(
+            input.as_bytes(),
+            &mut output,
+            language,
+            &CleanOptions::default(),
+            chunk_size,
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_stream_matches_whole_file_cleaning() {
+        let cases = [
+            (
+                "int a; /* multi\nline */ int b; // tail\r\n\r\n\r\n// é only\nint c = '/';\n",
+                Language::C,
+            ),
+            (
+                "def f():\n    \"\"\"doc # kept\n    more\"\"\"\n    return 1  # gone\n\n\n\nx = 'ü#'\n",
+                Language::Python,
+            ),
+            (
+                "fn f() { /* a /* nested */ b */ let s = \"// str\"; }\n",
+                Language::Rust,
+            ),
+            (
+                "#!/usr/bin/env python3\n# gone\nx = 1  # gone\n",
+                Language::Python,
+            ),
+            (
+                "<!-- head -->\n<p>ü</p>\n<script type=\"x\">let r = /a\\/b/; // js\n</script><style>/* css */ p {}</style>\n",
+                Language::Html,
+            ),
+        ];
+
+        for (input, language) in cases {
+            let expected = clean_comments(input, language);
+            for chunk_size in [1, 2, 3, 5, 7, 64] {
+                assert_eq!(
+                    stream(input, language, chunk_size),
+                    expected,
+                    "{} with chunk size {}",
+                    language,
+                    chunk_size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_counts_removed_comments() {
+        let report = clean_stream(
+            "a // one\nb /* two */\n".as_bytes(),
+            io::sink(),
+            Language::Cpp,
+            &CleanOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.comments_removed, 2);
+        assert!(report.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_stream_reports_unterminated_comment() {
+        let input = "int a;\nchar *s = \"é\"; /* open\nint b;\n";
+        for chunk_size in [1, 3, 64] {
+            let report = clean_stream_chunked(
+                input.as_bytes(),
+                io::sink(),
+                Language::C,
+                &CleanOptions::default(),
+                chunk_size,
+            )
+            .unwrap();
+            let found: Vec<_> = report
+                .diagnostics
+                .iter()
+                .map(|d| (d.kind, d.line, d.column))
+                .collect();
+            assert_eq!(found, vec![(DiagnosticKind::UnterminatedComment, 2, 16)]);
+        }
+    }
+
+    #[test]
+    fn test_stream_rejects_invalid_utf8() {
+        let result = clean_stream(
+            &b"int a; \xff\xfe"[..],
+            io::sink(),
+            Language::C,
+            &CleanOptions::default(),
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
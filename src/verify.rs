@@ -0,0 +1,135 @@
+use crate::language::Language;
+use crate::lexer::{self, CommentSpan};
+use std::error::Error;
+use std::fmt;
+
+/// Where the code of a cleaned file first differs from the original.
+///
+/// Positions are 1-based line and column pairs; a missing token means the
+/// input ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub original: (usize, usize),
+    pub cleaned: (usize, usize),
+    pub expected: Option<String>,
+    pub found: Option<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let token = |t: &Option<String>| match t {
+            Some(t) => format!("`{}`", t),
+            None => "end of file".to_string(),
+        };
+        write!(
+            f,
+            "code changed at line {}, column {}: expected {}, found {} at line {}, column {} of the output",
+            self.original.0,
+            self.original.1,
+            token(&self.expected),
+            token(&self.found),
+            self.cleaned.0,
+            self.cleaned.1
+        )
+    }
+}
+
+impl Error for Mismatch {}
+
+/// Checks that `cleaned` differs from `original` only in comments and
+/// whitespace.
+///
+/// Both inputs are lexed again and split into whitespace-separated tokens,
+/// with every comment acting as a separator. Removing a comment must leave
+/// the token sequence untouched, which catches tokens glued together by the
+/// removal (`a/**/b`) as well as output that lexes differently from its
+/// source.
+pub fn verify_clean(original: &str, cleaned: &str, language: Language) -> Result<(), Mismatch> {
+    let before = code_tokens(original, &lexer::lex_comments(original, language));
+    let after = code_tokens(cleaned, &lexer::lex_comments(cleaned, language));
+
+    let position = before
+        .iter()
+        .zip(&after)
+        .position(|((_, a), (_, b))| a != b)
+        .or_else(|| (before.len() != after.len()).then(|| before.len().min(after.len())));
+
+    match position {
+        None => Ok(()),
+        Some(i) => Err(Mismatch {
+            original: line_column(original, before.get(i).map_or(original.len(), |t| t.0)),
+            cleaned: line_column(cleaned, after.get(i).map_or(cleaned.len(), |t| t.0)),
+            expected: before.get(i).map(|t| t.1.to_string()),
+            found: after.get(i).map(|t| t.1.to_string()),
+        }),
+    }
+}
+
+fn code_tokens<'a>(content: &'a str, spans: &[CommentSpan]) -> Vec<(usize, &'a str)> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    let segments = spans
+        .iter()
+        .map(|s| s.range.clone())
+        .chain(std::iter::once(content.len()..content.len()));
+    for range in segments {
+        let mut start = None;
+        for (i, c) in content[pos..range.start].char_indices() {
+            match (c.is_whitespace(), start) {
+                (true, Some(s)) => {
+                    tokens.push((pos + s, &content[pos + s..pos + i]));
+                    start = None;
+                }
+                (false, None) => start = Some(i),
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            tokens.push((pos + s, &content[pos + s..range.start]));
+        }
+        pos = range.end;
+    }
+
+    tokens
+}
+
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cleaner::clean_comments;
+
+    #[test]
+    fn test_clean_output_verifies() {
+        let input = "int a = 1; // one\n/* two */\nchar *s = \"/* kept */\";\n";
+        assert_eq!(
+            verify_clean(input, &clean_comments(input, Language::C), Language::C),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_glued_tokens_are_reported() {
+        let mismatch = verify_clean("x = a -/**/- b;\n", "x = a -- b;\n", Language::C).unwrap_err();
+        assert_eq!(mismatch.original, (1, 7));
+        assert_eq!(mismatch.expected.as_deref(), Some("-"));
+        assert_eq!(mismatch.found.as_deref(), Some("--"));
+    }
+
+    #[test]
+    fn test_dropped_code_is_reported() {
+        let mismatch = verify_clean("a;\nb; // c\n", "a;\n", Language::C).unwrap_err();
+        assert_eq!(mismatch.original, (2, 1));
+        assert_eq!(mismatch.cleaned, (2, 1));
+        assert_eq!(mismatch.found, None);
+    }
+}